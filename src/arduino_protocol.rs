@@ -0,0 +1,217 @@
+//! Framing of the serial link to the Arduino, used in both directions:
+//! start byte (0xA5), payload length, sequence number, payload, CRC-8 of everything after the start byte.
+//! The first byte of every payload is the message type.
+
+pub const START_BYTE: u8 = 0xA5;
+/// Longest payload either side sends, a longer length byte means the frame is corrupted
+pub const MAX_PAYLOAD_LEN: usize = 32;
+/// Start byte, length, sequence number and CRC
+const FRAME_OVERHEAD: usize = 4;
+
+/// Arduino to car: the encoder position of the drive motor, i32 clicks
+const MESSAGE_ENCODER_POSITION: u8 = 0x81;
+/// Arduino to car: a command was applied, the sequence number of its frame and the applied value as an i32
+const MESSAGE_ACK: u8 = 0x82;
+
+/// CRC-8 with polynomial 0x07 (CRC-8/SMBUS), cheap enough to compute on the Arduino without a table
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub seq: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.payload.len() + FRAME_OVERHEAD);
+        bytes.push(START_BYTE);
+        bytes.push(self.payload.len() as u8);
+        bytes.push(self.seq);
+        bytes.extend_from_slice(&self.payload);
+        bytes.push(crc8(&bytes[1..]));
+        bytes
+    }
+}
+
+/// Splits the bytes coming from the serial port into frames, skipping ahead to the next start byte whenever a frame
+/// turns out to be corrupted
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    /// Number of frames thrown away because their CRC didn't match
+    pub crc_failures: u32,
+    /// Number of bytes skipped while looking for the start of a frame
+    pub skipped_bytes: u32,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next complete frame in the buffer, None if more bytes are needed
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        loop {
+            let start = self
+                .buffer
+                .iter()
+                .position(|&byte| byte == START_BYTE)
+                .unwrap_or(self.buffer.len());
+            self.skipped_bytes += start as u32;
+            self.buffer.drain(..start);
+
+            let &len = self.buffer.get(1)?;
+            let len = len as usize;
+            if len > MAX_PAYLOAD_LEN {
+                self.skip_start_byte();
+                continue;
+            }
+            if self.buffer.len() < len + FRAME_OVERHEAD {
+                return None;
+            }
+            let crc = self.buffer[len + FRAME_OVERHEAD - 1];
+            if crc8(&self.buffer[1..len + FRAME_OVERHEAD - 1]) != crc {
+                self.crc_failures += 1;
+                // the start byte was probably a payload byte of a frame which lost its own start, look again after it
+                self.skip_start_byte();
+                continue;
+            }
+            let frame = Frame {
+                seq: self.buffer[2],
+                payload: self.buffer[3..len + 3].to_vec(),
+            };
+            self.buffer.drain(..len + FRAME_OVERHEAD);
+            return Some(frame);
+        }
+    }
+
+    fn skip_start_byte(&mut self) {
+        self.buffer.remove(0);
+        self.skipped_bytes += 1;
+    }
+}
+
+/// A message from the Arduino
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArduinoMessage {
+    EncoderPosition(i32),
+    /// The command sent in the frame with sequence number `seq` was applied, `value` is what the Arduino actually
+    /// used, which may be clamped
+    Ack {
+        seq: u8,
+        value: i32,
+    },
+}
+
+impl ArduinoMessage {
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        match payload {
+            [MESSAGE_ENCODER_POSITION, position @ ..] if position.len() == 4 => Some(
+                ArduinoMessage::EncoderPosition(i32::from_le_bytes(position.try_into().unwrap())),
+            ),
+            [MESSAGE_ACK, seq, value @ ..] if value.len() == 4 => Some(ArduinoMessage::Ack {
+                seq: *seq,
+                value: i32::from_le_bytes(value.try_into().unwrap()),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The payload the Arduino sends, only the tests play the Arduino's side
+    #[cfg(test)]
+    pub fn to_payload(&self) -> Vec<u8> {
+        match self {
+            ArduinoMessage::EncoderPosition(position) => {
+                let mut payload = vec![MESSAGE_ENCODER_POSITION];
+                payload.extend_from_slice(&position.to_le_bytes());
+                payload
+            }
+            ArduinoMessage::Ack { seq, value } => {
+                let mut payload = vec![MESSAGE_ACK, *seq];
+                payload.extend_from_slice(&value.to_le_bytes());
+                payload
+            }
+        }
+    }
+}
+
+#[test]
+fn test_crc8() {
+    // the check value of CRC-8/SMBUS
+    assert_eq!(crc8(b"123456789"), 0xF4);
+}
+
+#[test]
+fn test_frame_round_trip() {
+    let frames = [
+        Frame {
+            seq: 7,
+            payload: ArduinoMessage::EncoderPosition(-1234).to_payload(),
+        },
+        Frame {
+            seq: 8,
+            payload: ArduinoMessage::Ack {
+                seq: 3,
+                value: 1450,
+            }
+            .to_payload(),
+        },
+    ];
+    let bytes: Vec<u8> = frames.iter().flat_map(Frame::encode).collect();
+    let mut decoder = FrameDecoder::new();
+    // bytes arrive in pieces
+    for chunk in bytes.chunks(3) {
+        decoder.push(chunk);
+    }
+    assert_eq!(decoder.next_frame(), Some(frames[0].clone()));
+    assert_eq!(decoder.next_frame(), Some(frames[1].clone()));
+    assert_eq!(decoder.next_frame(), None);
+    assert_eq!(
+        ArduinoMessage::from_payload(&frames[0].payload),
+        Some(ArduinoMessage::EncoderPosition(-1234))
+    );
+    assert_eq!(
+        ArduinoMessage::from_payload(&frames[1].payload),
+        Some(ArduinoMessage::Ack {
+            seq: 3,
+            value: 1450
+        })
+    );
+}
+
+#[test]
+fn test_frame_resync() {
+    let frame = Frame {
+        seq: 1,
+        payload: ArduinoMessage::EncoderPosition(5000).to_payload(),
+    };
+    let mut corrupted = frame.encode();
+    corrupted[4] ^= 0xff;
+
+    let mut decoder = FrameDecoder::new();
+    // garbage, a start byte with a bogus length, a corrupted frame and finally a good one
+    decoder.push(&[0x12, 0x34, START_BYTE, 0xff]);
+    decoder.push(&corrupted);
+    decoder.push(&frame.encode());
+    assert_eq!(decoder.next_frame(), Some(frame));
+    assert_eq!(decoder.crc_failures, 1);
+    assert_eq!(decoder.next_frame(), None);
+}
@@ -0,0 +1,328 @@
+//! Calibration of the odometry: drives arcs at a range of servo positions and measures each with the lidar, then fits
+//! the distance per encoder click and the curvature of each servo position, and estimates the odometry noise from
+//! what the fit doesn't explain
+
+use std::f64::consts::PI;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lstsq::lstsq;
+use nalgebra::{DMatrix, DVector, Vector3};
+use tokio::sync::{mpsc, watch};
+
+use crate::drive_distance::{drive_distance, DriveDistanceConfig, DriveDistanceError};
+use crate::lidar::LidarScan;
+use crate::motor_control::{MotorCommand, MotorControlError, MotorControlRequest};
+use crate::odometry::OdometryNoise;
+use crate::pose_graph::icp_least_squares;
+use crate::steering::{SteeringCalibration, SteeringCalibrationError};
+
+#[derive(Debug, Clone)]
+pub struct CalibrationConfig {
+    /// Servo positions to drive with, each driven forward and then back to roughly where it started
+    pub servo_us: Vec<u16>,
+    /// How far each run drives in millimeters, short enough for the scans at both ends to match
+    pub run_distance: f32,
+    /// How long the car is left to come to rest before a scan is taken
+    pub settle_time: Duration,
+    pub icp_iterations: usize,
+}
+
+impl Default for CalibrationConfig {
+    fn default() -> Self {
+        Self {
+            servo_us: vec![1100, 1200, 1300, 1450, 1600, 1700, 1800],
+            run_distance: 400.0,
+            settle_time: Duration::from_millis(500),
+            icp_iterations: 50,
+        }
+    }
+}
+
+/// One drive at a constant servo position
+#[derive(Debug, Clone)]
+pub struct CalibrationRun {
+    pub servo_us: u16,
+    /// Encoder clicks driven, negative if the run went backwards
+    pub clicks: i32,
+    /// Pose at the end of the run in the frame of the start as x, y in millimeters and the heading in radians, from
+    /// matching the scans at both ends
+    pub motion: Vector3<f64>,
+}
+
+#[derive(Debug)]
+pub enum CalibrationError {
+    Motor(MotorControlError),
+    Drive(DriveDistanceError),
+    LidarStopped,
+    /// The scans before and after this servo position's run didn't match
+    Icp {
+        servo_us: u16,
+    },
+    /// The runs don't determine the calibration
+    Fit(&'static str),
+    Calibration(SteeringCalibrationError),
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalibrationError::Motor(err) => write!(f, "motor request failed: {}", err),
+            CalibrationError::Drive(err) => write!(f, "run failed: {}", err),
+            CalibrationError::LidarStopped => write!(f, "the lidar stopped"),
+            CalibrationError::Icp { servo_us } => {
+                write!(f, "scans didn't match after the run at {}us", servo_us)
+            }
+            CalibrationError::Fit(reason) => write!(f, "can't fit the runs: {}", reason),
+            CalibrationError::Calibration(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for CalibrationError {}
+
+/// Drive the runs and fit a new calibration to them, starting from `current` for the servo limits and the run lengths.
+/// The car needs room for the tightest arc and walls around it for the scans to match, and should be left alone while
+/// the drive mode is manual.
+pub async fn run_calibration(
+    config: &CalibrationConfig,
+    current: &SteeringCalibration,
+    commands: &mpsc::Sender<MotorCommand>,
+    motor_position: watch::Receiver<i32>,
+    mut scans: watch::Receiver<Option<Arc<LidarScan>>>,
+) -> Result<SteeringCalibration, CalibrationError> {
+    let drive_config = DriveDistanceConfig {
+        mm_per_click: current.mm_per_click,
+        ..Default::default()
+    };
+    let mut runs = Vec::new();
+    for &servo_us in &config.servo_us {
+        set_servo(commands, servo_us).await?;
+        for distance in [config.run_distance, -config.run_distance] {
+            tokio::time::sleep(config.settle_time).await;
+            let start_scan = next_scan(&mut scans).await?;
+            let start_position = *motor_position.borrow();
+            drive_distance(commands, motor_position.clone(), distance, &drive_config)
+                .await
+                .map_err(CalibrationError::Drive)?;
+            tokio::time::sleep(config.settle_time).await;
+            let end_scan = next_scan(&mut scans).await?;
+            let clicks = *motor_position.borrow() - start_position;
+            // the transform taking the end scan onto the start scan is the pose of the end in the start's frame
+            let motion = icp_least_squares(
+                &end_scan.to_cartesian_points(),
+                &start_scan.to_cartesian_points(),
+                config.icp_iterations,
+            )
+            .map_err(|_| CalibrationError::Icp { servo_us })?;
+            println!(
+                "calibration run at {}us: {} clicks, moved {:?}",
+                servo_us, clicks, motion
+            );
+            runs.push(CalibrationRun {
+                servo_us,
+                clicks,
+                motion,
+            });
+        }
+    }
+    set_servo(commands, current.center_us).await?;
+    fit_calibration(&runs, current)
+}
+
+async fn set_servo(
+    commands: &mpsc::Sender<MotorCommand>,
+    microseconds: u16,
+) -> Result<(), CalibrationError> {
+    let (command, reply) =
+        MotorCommand::with_reply(MotorControlRequest::SetServoPosition { microseconds });
+    if commands.send(command).await.is_err() {
+        return Err(CalibrationError::Motor(MotorControlError::LinkDown));
+    }
+    match reply.await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(err)) => Err(CalibrationError::Motor(err)),
+        Err(_) => Err(CalibrationError::Motor(MotorControlError::LinkDown)),
+    }
+}
+
+/// The first scan started after this is called, so it isn't smeared by motion before the car came to rest
+async fn next_scan(
+    scans: &mut watch::Receiver<Option<Arc<LidarScan>>>,
+) -> Result<Arc<LidarScan>, CalibrationError> {
+    scans.borrow_and_update();
+    for _ in 0..2 {
+        scans
+            .changed()
+            .await
+            .map_err(|_| CalibrationError::LidarStopped)?;
+    }
+    scans
+        .borrow_and_update()
+        .clone()
+        .ok_or(CalibrationError::LidarStopped)
+}
+
+/// Least squares fit of the distance per click over the arc lengths of all runs, and of a polynomial in the servo
+/// position over the curvature of each run. The table has an entry per servo position driven.
+pub fn fit_calibration(
+    runs: &[CalibrationRun],
+    current: &SteeringCalibration,
+) -> Result<SteeringCalibration, CalibrationError> {
+    let mut servo_us: Vec<u16> = runs.iter().map(|run| run.servo_us).collect();
+    servo_us.sort_unstable();
+    servo_us.dedup();
+    if servo_us.len() < 2 {
+        return Err(CalibrationError::Fit(
+            "at least two servo positions are needed",
+        ));
+    }
+    if runs.iter().any(|run| run.clicks == 0) {
+        return Err(CalibrationError::Fit("a run didn't move the encoder"));
+    }
+
+    // arc lengths driven, signed like the clicks
+    let arcs: Vec<(f64, f64)> = runs
+        .iter()
+        .map(|run| {
+            let heading = (run.motion.z + PI).rem_euclid(2.0 * PI) - PI;
+            let chord = run.motion.xy().norm();
+            let half = heading.abs() / 2.0;
+            let length = if half < 1e-6 {
+                chord
+            } else {
+                chord * half / half.sin()
+            };
+            (length * run.clicks.signum() as f64, heading)
+        })
+        .collect();
+
+    let clicks = DMatrix::from_iterator(
+        runs.len(),
+        1,
+        runs.iter().map(|run| run.clicks.abs() as f64),
+    );
+    let lengths = DVector::from_iterator(runs.len(), arcs.iter().map(|(length, _)| length.abs()));
+    let mm_per_click = lstsq(&clicks, &lengths, 1e-12)
+        .map_err(CalibrationError::Fit)?
+        .solution[0];
+
+    // the servo position is scaled to about -1..1 to keep the powers comparable
+    let scale = |us: u16| (us as f64 - current.center_us as f64) / 500.0;
+    let degree = (servo_us.len() - 1).min(2);
+    let powers = |us: u16| (0..=degree).map(move |power| scale(us).powi(power as i32));
+    let a = DMatrix::from_row_iterator(
+        runs.len(),
+        degree + 1,
+        runs.iter().flat_map(|run| powers(run.servo_us)),
+    );
+    let curvatures = DVector::from_iterator(
+        runs.len(),
+        arcs.iter().map(|(length, heading)| heading / length),
+    );
+    let coefficients = lstsq(&a, &curvatures, 1e-12)
+        .map_err(CalibrationError::Fit)?
+        .solution;
+    let table = servo_us
+        .iter()
+        .map(|&us| {
+            let curvature = powers(us)
+                .zip(coefficients.iter())
+                .map(|(power, coefficient)| power * coefficient)
+                .sum();
+            (us, curvature)
+        })
+        .collect();
+
+    let mut calibration = SteeringCalibration {
+        center_us: current.center_us,
+        min_us: current.min_us,
+        max_us: current.max_us,
+        table,
+        mm_per_click,
+        noise: current.noise.clone(),
+    };
+    calibration
+        .validate()
+        .map_err(CalibrationError::Calibration)?;
+    calibration.center_us = calibration.curvature_to_servo_us(0.0);
+    calibration.noise = estimate_noise(runs, &arcs, &calibration)?;
+    Ok(calibration)
+}
+
+/// Noise variances matching the residuals of the runs under the fitted calibration, with each variance growing with
+/// the distance like the noise model assumes
+fn estimate_noise(
+    runs: &[CalibrationRun],
+    arcs: &[(f64, f64)],
+    calibration: &SteeringCalibration,
+) -> Result<OdometryNoise, CalibrationError> {
+    let mut distance_residuals = 0.0;
+    let mut total_distance = 0.0;
+    let mut heading_model = Vec::with_capacity(runs.len() * 2);
+    let mut heading_residuals = Vec::with_capacity(runs.len());
+    for (run, &(length, heading)) in runs.iter().zip(arcs) {
+        let dist = run.clicks as f64 * calibration.mm_per_click;
+        let curvature = calibration.servo_us_to_curvature(run.servo_us);
+        distance_residuals += (length - dist).powi(2);
+        total_distance += dist.abs();
+        heading_model.extend([dist.abs(), curvature.abs() * dist * dist]);
+        heading_residuals.push((heading - curvature * dist).powi(2));
+    }
+    let a = DMatrix::from_row_slice(runs.len(), 2, &heading_model);
+    let b = DVector::from_vec(heading_residuals);
+    let heading = lstsq(&a, &b, 1e-18)
+        .map_err(CalibrationError::Fit)?
+        .solution;
+    Ok(OdometryNoise {
+        distance: distance_residuals / total_distance,
+        // a negative variance only means the runs didn't show any of that kind of noise
+        heading: heading[0].max(0.0),
+        steering: heading[1].max(0.0),
+    })
+}
+
+#[test]
+fn test_fit_calibration() {
+    let mm_per_click = 0.2;
+    let curvature = |us: u16| (us as f64 - 1460.0) * 5e-6;
+    // the pose at the end of an arc of `length` millimeters
+    let run = |servo_us: u16, length: f64| {
+        let heading = curvature(servo_us) * length;
+        let motion = if heading == 0.0 {
+            Vector3::new(length, 0.0, 0.0)
+        } else {
+            let radius = 1.0 / curvature(servo_us);
+            Vector3::new(
+                radius * heading.sin(),
+                radius * (1.0 - heading.cos()),
+                heading.rem_euclid(2.0 * PI),
+            )
+        };
+        CalibrationRun {
+            servo_us,
+            clicks: (length / mm_per_click).round() as i32,
+            motion,
+        }
+    };
+    let runs: Vec<CalibrationRun> = [1100, 1300, 1460, 1600, 1800]
+        .into_iter()
+        .flat_map(|us| [run(us, 400.0), run(us, -400.0)])
+        .collect();
+
+    let calibration = fit_calibration(&runs, &SteeringCalibration::default()).unwrap();
+    assert!((calibration.mm_per_click - mm_per_click).abs() < 1e-6);
+    assert_eq!(calibration.center_us, 1460);
+    for (us, fitted) in &calibration.table {
+        assert!((fitted - curvature(*us)).abs() < 1e-8);
+    }
+    // and it survives the round trip through the file
+    let loaded = SteeringCalibration::parse(&calibration.to_text()).unwrap();
+    assert_eq!(loaded.center_us, 1460);
+    assert_eq!(loaded.mm_per_click, calibration.mm_per_click);
+    // the runs are exact, so there's no noise to be seen
+    assert!(calibration.noise.distance < 1e-6);
+    assert!(calibration.noise.heading < 1e-12);
+    assert_eq!(loaded.noise.steering, calibration.noise.steering);
+}
@@ -0,0 +1,211 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{mpsc, watch};
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::free_space::{footprint_clearance, Direction, Footprint};
+use crate::lidar::LidarScan;
+use crate::motor_control::{EmergencyStopReason, MotorCommand, MotorControlRequest};
+use crate::reactive_corridor::ReactiveCorridorMode;
+use crate::speed_control::SpeedController;
+use crate::steering::SteeringCalibration;
+
+#[derive(Debug, Clone)]
+pub struct ControlLoopConfig {
+    /// How many times a second the control loop runs
+    pub rate: f64,
+    /// Outline of the car, used to check for obstacles in the way
+    pub footprint: Footprint,
+    /// Emergency stop if the car is driving towards something closer than this many millimeters to its footprint
+    pub proximity_stop_distance: f64,
+    /// Curvature the servo steers, the proximity check looks along it
+    pub steering: SteeringCalibration,
+}
+
+impl Default for ControlLoopConfig {
+    fn default() -> Self {
+        Self {
+            rate: 50.0,
+            footprint: Footprint {
+                front: 250.0,
+                rear: 150.0,
+                half_width: 100.0,
+            },
+            proximity_stop_distance: 100.0,
+            steering: SteeringCalibration::default(),
+        }
+    }
+}
+
+/// How the car is driven, autonomy modes decide on motor commands every tick of the control loop
+#[derive(Debug, Clone)]
+pub enum DriveMode {
+    /// Driven by the clients, the control loop doesn't send anything
+    Manual,
+    ReactiveCorridor(ReactiveCorridorMode),
+    /// Hold the target speed of the controller, steering is left to the clients
+    Speed(SpeedController),
+}
+
+impl DriveMode {
+    fn step(&mut self, inputs: &ControlInputs) -> Vec<MotorControlRequest> {
+        match self {
+            DriveMode::Manual => Vec::new(),
+            DriveMode::ReactiveCorridor(mode) => mode.step(inputs),
+            DriveMode::Speed(controller) => controller.step(inputs),
+        }
+    }
+
+    /// Commands bringing the car to a standstill when the mode is left
+    fn stop(&mut self) -> Vec<MotorControlRequest> {
+        match self {
+            DriveMode::Manual => Vec::new(),
+            DriveMode::ReactiveCorridor(mode) => mode.stop(),
+            DriveMode::Speed(controller) => controller.stop(),
+        }
+    }
+}
+
+/// The most recent sensor data when the control loop ticks
+pub struct ControlInputs {
+    pub scan: Option<Arc<LidarScan>>,
+    /// Whether the scan arrived since the last tick
+    pub new_scan: bool,
+    /// Encoder position of the drive motor in clicks
+    pub motor_position: i32,
+    /// Seconds since the last tick
+    pub dt: f64,
+}
+
+/// The channels between the control loop and the motor task
+pub struct MotorChannels {
+    pub commands: mpsc::Sender<MotorCommand>,
+    /// The motor task's emergency stop channel, for proximity stops
+    pub stops: mpsc::UnboundedSender<MotorCommand>,
+    pub motor_position: watch::Receiver<i32>,
+    /// Servo position the Arduino last acknowledged
+    pub servo_us: Arc<Mutex<u16>>,
+    /// Why the car is latched in an emergency stop, the modes don't run while it is
+    pub emergency_stop: watch::Receiver<Option<EmergencyStopReason>>,
+}
+
+/// Runs the drive mode at a fixed rate on the latest scan and encoder position, sending its commands to the motor task
+pub struct ControlLoop {
+    pub config: ControlLoopConfig,
+    pub mode: DriveMode,
+    /// The mode to switch to, the current one is stopped first
+    modes: watch::Receiver<DriveMode>,
+    scans: watch::Receiver<Option<Arc<LidarScan>>>,
+    motor: MotorChannels,
+    /// Encoder position when the last scan arrived, to tell which way the car is moving
+    scan_motor_position: i32,
+}
+
+impl ControlLoop {
+    pub fn new(
+        config: ControlLoopConfig,
+        mut modes: watch::Receiver<DriveMode>,
+        scans: watch::Receiver<Option<Arc<LidarScan>>>,
+        motor: MotorChannels,
+    ) -> Self {
+        let mode = modes.borrow_and_update().clone();
+        ControlLoop {
+            config,
+            mode,
+            modes,
+            scans,
+            motor,
+            scan_motor_position: 0,
+        }
+    }
+
+    /// Run until the motor task stops taking commands
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / self.config.rate));
+        // a late tick should act on the latest data instead of catching up on stale ticks
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut last_tick = None;
+        loop {
+            let tick = interval.tick().await;
+            let dt = last_tick.map_or(1.0 / self.config.rate, |last_tick: Instant| {
+                (tick - last_tick).as_secs_f64()
+            });
+            last_tick = Some(tick);
+            let mut commands = Vec::new();
+            if self.modes.has_changed().unwrap_or(false) {
+                let mode = self.modes.borrow_and_update().clone();
+                match (&mut self.mode, mode) {
+                    // a new target speed shouldn't throw away the speed estimate or stop the car in between
+                    (DriveMode::Speed(controller), DriveMode::Speed(new)) => {
+                        controller.target = new.target;
+                    }
+                    (_, mode) => {
+                        commands.extend(self.mode.stop());
+                        self.mode = mode;
+                        println!("Switching to drive mode {:?}", self.mode);
+                    }
+                }
+            }
+            // a mode running against the latch would only wind up, and drive off once the stop is reset
+            let latched = *self.motor.emergency_stop.borrow();
+            if let (Some(reason), false) = (latched, matches!(self.mode, DriveMode::Manual)) {
+                println!(
+                    "Emergency stopped ({:?}), leaving drive mode {:?}",
+                    reason, self.mode
+                );
+                commands.extend(self.mode.stop());
+                self.mode = DriveMode::Manual;
+            }
+            let inputs = ControlInputs {
+                new_scan: self.scans.has_changed().unwrap_or(false),
+                scan: self.scans.borrow_and_update().clone(),
+                motor_position: *self.motor.motor_position.borrow_and_update(),
+                dt,
+            };
+            if let Some(stop) = self.check_proximity(&inputs) {
+                // the mode would keep driving once the stop is reset
+                commands.extend(self.mode.stop());
+                self.mode = DriveMode::Manual;
+                if self.motor.stops.send(stop.into()).is_err() {
+                    println!("Motor task stopped, stopping the control loop");
+                    return;
+                }
+            }
+            commands.extend(self.mode.step(&inputs));
+            for command in commands {
+                if self.motor.commands.send(command.into()).await.is_err() {
+                    println!("Motor task stopped, stopping the control loop");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// An emergency stop if the car is driving towards something too close to it
+    fn check_proximity(&mut self, inputs: &ControlInputs) -> Option<MotorControlRequest> {
+        let (Some(scan), true) = (&inputs.scan, inputs.new_scan) else {
+            return None;
+        };
+        let moved = inputs.motor_position - self.scan_motor_position;
+        self.scan_motor_position = inputs.motor_position;
+        let direction = match moved {
+            0 => return None,
+            1.. => Direction::Forward,
+            _ => Direction::Backward,
+        };
+        // along the arc the car is steering, obstacles beside a straight path don't stop it in a turn
+        let servo_us = *self.motor.servo_us.lock().unwrap();
+        let clearance = footprint_clearance(
+            &scan.to_cartesian_points(),
+            &self.config.footprint,
+            self.config.steering.servo_us_to_curvature(servo_us),
+            direction,
+        )?;
+        (clearance < self.config.proximity_stop_distance).then_some(
+            MotorControlRequest::EmergencyStop(EmergencyStopReason::Proximity {
+                distance: clearance,
+            }),
+        )
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use crate::motor_control::{MotorCommand, MotorControlError, MotorControlRequest};
+use crate::odometry::MM_PER_CLICK;
+
+#[derive(Debug, Clone)]
+pub struct DriveDistanceConfig {
+    /// The move is done once the encoder is within this many clicks of the setpoint
+    pub tolerance: i32,
+    /// Give up if the encoder doesn't move for this long before reaching the setpoint
+    pub stall_timeout: Duration,
+    /// Distance the car travels per encoder click
+    pub mm_per_click: f64,
+}
+
+impl Default for DriveDistanceConfig {
+    fn default() -> Self {
+        Self {
+            tolerance: 25,
+            stall_timeout: Duration::from_secs(1),
+            mm_per_click: MM_PER_CLICK as f64,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DriveDistanceError {
+    /// The distance isn't a number or the setpoint would overflow the encoder position
+    InvalidDistance(f32),
+    /// The position setpoint didn't reach the Arduino
+    Motor(MotorControlError),
+    /// The encoder stopped moving short of the setpoint, after driving this many millimeters
+    Stalled { traveled: f32 },
+}
+
+impl fmt::Display for DriveDistanceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DriveDistanceError::InvalidDistance(millimeters) => {
+                write!(f, "can't drive {}mm", millimeters)
+            }
+            DriveDistanceError::Motor(err) => write!(f, "motor request failed: {}", err),
+            DriveDistanceError::Stalled { traveled } => {
+                write!(f, "stalled after {:.0}mm", traveled)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DriveDistanceError {}
+
+/// Drive `millimeters` forward (backward if negative) on the encoder, returning the distance actually traveled once
+/// the motor settles on the setpoint
+pub async fn drive_distance(
+    commands: &mpsc::Sender<MotorCommand>,
+    mut motor_position: watch::Receiver<i32>,
+    millimeters: f32,
+    config: &DriveDistanceConfig,
+) -> Result<f32, DriveDistanceError> {
+    let start = *motor_position.borrow_and_update();
+    let clicks = (millimeters as f64 / config.mm_per_click).round();
+    // the cast would saturate infinities and turn NaN into 0 clicks
+    if !clicks.is_finite() || clicks.abs() > i32::MAX as f64 {
+        return Err(DriveDistanceError::InvalidDistance(millimeters));
+    }
+    let Some(target) = start.checked_add(clicks as i32) else {
+        return Err(DriveDistanceError::InvalidDistance(millimeters));
+    };
+    let (command, reply) =
+        MotorCommand::with_reply(MotorControlRequest::SetMotorPosition { clicks: target });
+    if commands.send(command).await.is_err() {
+        return Err(DriveDistanceError::Motor(MotorControlError::LinkDown));
+    }
+    match reply.await {
+        Ok(Ok(_)) => {}
+        Ok(Err(err)) => return Err(DriveDistanceError::Motor(err)),
+        Err(_) => return Err(DriveDistanceError::Motor(MotorControlError::LinkDown)),
+    }
+
+    let mut position = *motor_position.borrow_and_update();
+    let mut last_moved = Instant::now();
+    let traveled = |position: i32| ((position - start) as f64 * config.mm_per_click) as f32;
+    while (target - position).abs() > config.tolerance {
+        match tokio::time::timeout_at(last_moved + config.stall_timeout, motor_position.changed())
+            .await
+        {
+            Ok(Ok(())) => {
+                let new_position = *motor_position.borrow_and_update();
+                if new_position != position {
+                    position = new_position;
+                    last_moved = Instant::now();
+                }
+            }
+            Ok(Err(_)) => return Err(DriveDistanceError::Motor(MotorControlError::LinkDown)),
+            Err(_) => {
+                // don't leave the Arduino pushing against whatever is in the way
+                let _ = commands
+                    .send(MotorControlRequest::SetMotorOutput(0).into())
+                    .await;
+                return Err(DriveDistanceError::Stalled {
+                    traveled: traveled(position),
+                });
+            }
+        }
+    }
+    Ok(traveled(position))
+}
+
+#[tokio::test]
+async fn test_drive_distance() {
+    let (tx, mut rx) = mpsc::channel::<MotorCommand>(4);
+    let (motor_position_tx, motor_position_rx) = watch::channel(1000);
+    let config = DriveDistanceConfig {
+        tolerance: 5,
+        stall_timeout: Duration::from_millis(50),
+        ..Default::default()
+    };
+    // a motor which drives towards its setpoint until it hits something at 2000 clicks
+    tokio::spawn(async move {
+        while let Some(command) = rx.recv().await {
+            let MotorControlRequest::SetMotorPosition { clicks } = command.request else {
+                continue;
+            };
+            let _ = command.reply.unwrap().send(Ok(clicks));
+            let target = clicks.min(2000);
+            while *motor_position_tx.borrow() != target {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                motor_position_tx.send_modify(|position| *position = target.min(*position + 100));
+            }
+        }
+    });
+
+    let traveled = drive_distance(&tx, motor_position_rx.clone(), 100.0, &config)
+        .await
+        .unwrap();
+    assert!((traveled - 100.0).abs() < MM_PER_CLICK);
+    let result = drive_distance(&tx, motor_position_rx.clone(), 1000.0, &config).await;
+    assert!(matches!(result, Err(DriveDistanceError::Stalled { .. })));
+    for millimeters in [f32::NAN, f32::INFINITY, 1e12] {
+        let result = drive_distance(&tx, motor_position_rx.clone(), millimeters, &config).await;
+        assert!(matches!(
+            result,
+            Err(DriveDistanceError::InvalidDistance(_))
+        ));
+    }
+}
@@ -0,0 +1,318 @@
+use nalgebra::{Matrix2, Vector2};
+
+#[derive(Debug, Clone)]
+pub struct FeatureConfig {
+    /// Neighboring points further apart than this many millimeters belong to different clusters
+    pub cluster_gap: f64,
+    /// A segment is split at its furthest point if that point is further than this many millimeters from it, and two
+    /// neighboring segments are merged if the line through both stays within this distance of all their points
+    pub split_distance: f64,
+    /// Segments with fewer points are dropped
+    pub min_segment_points: usize,
+    /// Segments shorter than this many millimeters are dropped
+    pub min_segment_length: f64,
+    /// Standard deviation of a range measurement in millimeters, the lower bound of the noise used for the covariance
+    pub range_noise: f64,
+    /// Two segments only form a corner if the angle between their directions is at least this many radians
+    pub corner_min_angle: f64,
+    /// Two segments only form a corner if their line intersection is within this many millimeters of both their ends
+    pub corner_max_gap: f64,
+}
+
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        FeatureConfig {
+            cluster_gap: 150.0,
+            split_distance: 30.0,
+            min_segment_points: 5,
+            min_segment_length: 200.0,
+            range_noise: 10.0,
+            corner_min_angle: 60f64.to_radians(),
+            corner_max_gap: 150.0,
+        }
+    }
+}
+
+/// A straight piece of wall fitted to the points of a scan, in the frame of the car
+#[derive(Debug, Clone)]
+pub struct LineSegment {
+    /// The first point of the segment in scan order, projected on the line
+    pub start: Vector2<f64>,
+    /// The last point of the segment in scan order, projected on the line
+    pub end: Vector2<f64>,
+    /// Unit normal of the line, pointing from the line towards the car
+    pub normal: Vector2<f64>,
+    /// Distance from the car to the line in millimeters
+    pub distance: f64,
+    /// Covariance of the fit in the angle of the normal (radians) and the distance (millimeters)
+    pub covariance: Matrix2<f64>,
+    pub point_count: usize,
+}
+
+impl LineSegment {
+    pub fn length(&self) -> f64 {
+        (self.end - self.start).norm()
+    }
+
+    /// Unit vector from the start to the end of the segment
+    pub fn direction(&self) -> Vector2<f64> {
+        (self.end - self.start).normalize()
+    }
+}
+
+/// Where two segments meet, like the corner of a shelf
+#[derive(Debug, Clone)]
+pub struct Corner {
+    pub position: Vector2<f64>,
+    /// Angle between the directions of the two segments in radians
+    pub angle: f64,
+    /// Indices of the segments meeting here, in scan order
+    pub segments: (usize, usize),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScanFeatures {
+    pub segments: Vec<LineSegment>,
+    pub corners: Vec<Corner>,
+}
+
+/// Split the points of a scan in scan order into clusters at range jumps, fit line segments to every cluster with split
+/// and merge, and find the corners where neighboring segments meet
+pub fn extract_features(points: &[Vector2<f64>], config: &FeatureConfig) -> ScanFeatures {
+    let mut segments = Vec::new();
+    for cluster in clusters(points, config.cluster_gap) {
+        let mut ranges = Vec::new();
+        split(
+            &cluster,
+            0..cluster.len(),
+            config.split_distance,
+            &mut ranges,
+        );
+        for range in merge(&cluster, ranges, config.split_distance) {
+            if range.len() < config.min_segment_points {
+                continue;
+            }
+            let segment = fit_line(&cluster[range], config.range_noise);
+            if segment.length() >= config.min_segment_length {
+                segments.push(segment);
+            }
+        }
+    }
+    let corners = corners(&segments, config);
+    ScanFeatures { segments, corners }
+}
+
+/// Split points in scan order wherever two neighbors are too far apart, joining the first and last cluster if the scan
+/// wraps around through the same object
+fn clusters(points: &[Vector2<f64>], gap: f64) -> Vec<Vec<Vector2<f64>>> {
+    let mut clusters: Vec<Vec<Vector2<f64>>> = Vec::new();
+    for (i, point) in points.iter().enumerate() {
+        match clusters.last_mut() {
+            Some(cluster) if (point - points[i - 1]).norm() <= gap => cluster.push(*point),
+            _ => clusters.push(vec![*point]),
+        }
+    }
+    if clusters.len() > 1 {
+        let (first, last) = (&clusters[0], &clusters[clusters.len() - 1]);
+        if (first[0] - last[last.len() - 1]).norm() <= gap {
+            let mut wrapped = clusters.pop().unwrap();
+            wrapped.append(&mut clusters[0]);
+            clusters[0] = wrapped;
+        }
+    }
+    clusters
+}
+
+/// Distance from `point` to the line through `start` and `end`
+fn distance_to_line(point: &Vector2<f64>, start: &Vector2<f64>, end: &Vector2<f64>) -> f64 {
+    let direction = end - start;
+    let length = direction.norm();
+    if length == 0.0 {
+        return (point - start).norm();
+    }
+    (direction.x * (point.y - start.y) - direction.y * (point.x - start.x)).abs() / length
+}
+
+/// Index and distance of the point furthest from the line through the ends of `points`
+fn furthest_point(points: &[Vector2<f64>]) -> (usize, f64) {
+    let (start, end) = (&points[0], &points[points.len() - 1]);
+    points
+        .iter()
+        .enumerate()
+        .map(|(i, point)| (i, distance_to_line(point, start, end)))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap()
+}
+
+fn split(
+    points: &[Vector2<f64>],
+    range: std::ops::Range<usize>,
+    split_distance: f64,
+    ranges: &mut Vec<std::ops::Range<usize>>,
+) {
+    if range.len() > 2 {
+        let (i, distance) = furthest_point(&points[range.clone()]);
+        let i = range.start + i;
+        if distance > split_distance {
+            // the furthest point is the end of the first half and the start of the second
+            split(points, range.start..i + 1, split_distance, ranges);
+            split(points, i..range.end, split_distance, ranges);
+            return;
+        }
+    }
+    ranges.push(range);
+}
+
+/// Join neighboring ranges, which share their end point, as long as their points still lie on one line
+fn merge(
+    points: &[Vector2<f64>],
+    ranges: Vec<std::ops::Range<usize>>,
+    split_distance: f64,
+) -> Vec<std::ops::Range<usize>> {
+    let mut merged: Vec<std::ops::Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        if let Some(last) = merged.last_mut() {
+            let joined = last.start..range.end.max(last.end);
+            if furthest_point(&points[joined.clone()]).1 <= split_distance {
+                *last = joined;
+                continue;
+            }
+            // the shared end point belongs to the range before it
+            let start = range.start.max(last.end);
+            merged.push(start..range.end);
+        } else {
+            merged.push(range);
+        }
+    }
+    merged
+}
+
+/// Total least squares line through `points`, with the covariance of the normal angle and distance
+fn fit_line(points: &[Vector2<f64>], range_noise: f64) -> LineSegment {
+    let n = points.len() as f64;
+    let centroid = points.iter().sum::<Vector2<f64>>() / n;
+    let (mut sxx, mut syy, mut sxy) = (0.0, 0.0, 0.0);
+    for point in points {
+        let d = point - centroid;
+        sxx += d.x * d.x;
+        syy += d.y * d.y;
+        sxy += d.x * d.y;
+    }
+    // the normal minimizes the squared distances of the points to the line
+    let mut angle = 0.5 * (-2.0 * sxy).atan2(syy - sxx);
+    let mut distance = centroid.x * angle.cos() + centroid.y * angle.sin();
+    if distance < 0.0 {
+        distance = -distance;
+        angle += std::f64::consts::PI;
+    }
+    let away = Vector2::new(angle.cos(), angle.sin());
+    let direction = Vector2::new(-away.y, away.x);
+
+    let residuals: f64 = points
+        .iter()
+        .map(|point| (point.dot(&away) - distance).powi(2))
+        .sum();
+    let noise = if points.len() > 2 {
+        (residuals / (n - 2.0)).max(range_noise.powi(2))
+    } else {
+        range_noise.powi(2)
+    };
+    let spread: f64 = points
+        .iter()
+        .map(|point| (point - centroid).dot(&direction).powi(2))
+        .sum();
+    let angle_variance = noise / spread.max(f64::EPSILON);
+    // how far the centroid is along the line from the foot of the normal, which couples the angle to the distance
+    let along = centroid.dot(&direction);
+    let covariance = Matrix2::new(
+        angle_variance,
+        along * angle_variance,
+        along * angle_variance,
+        noise / n + along.powi(2) * angle_variance,
+    );
+
+    let project = |point: &Vector2<f64>| centroid + direction * (point - centroid).dot(&direction);
+    LineSegment {
+        start: project(&points[0]),
+        end: project(&points[points.len() - 1]),
+        normal: -away,
+        distance,
+        covariance,
+        point_count: points.len(),
+    }
+}
+
+fn corners(segments: &[LineSegment], config: &FeatureConfig) -> Vec<Corner> {
+    let mut corners = Vec::new();
+    if segments.len() < 2 {
+        return corners;
+    }
+    // the last segment can meet the first one where the scan wraps around, unless that is the same pair again
+    let pair_count = if segments.len() == 2 {
+        1
+    } else {
+        segments.len()
+    };
+    for i in 0..pair_count {
+        let j = (i + 1) % segments.len();
+        let (first, second) = (&segments[i], &segments[j]);
+        let angle = first
+            .direction()
+            .dot(&second.direction())
+            .clamp(-1.0, 1.0)
+            .acos();
+        if angle < config.corner_min_angle || angle > std::f64::consts::PI - config.corner_min_angle
+        {
+            continue;
+        }
+        // solve first.end + a * first.direction = second.start + b * second.direction
+        let (d1, d2) = (first.direction(), second.direction());
+        let denominator = d1.x * d2.y - d1.y * d2.x;
+        let offset = second.start - first.end;
+        let a = (offset.x * d2.y - offset.y * d2.x) / denominator;
+        let position = first.end + d1 * a;
+        if (position - first.end).norm() <= config.corner_max_gap
+            && (position - second.start).norm() <= config.corner_max_gap
+        {
+            corners.push(Corner {
+                position,
+                angle,
+                segments: (i, j),
+            });
+        }
+    }
+    corners
+}
+
+#[test]
+fn test_extract_features() {
+    use crate::lidar::LidarScan;
+
+    // the corner of two walls, 1000mm ahead and 1000mm to the left
+    let points: Vec<_> = (0..=232)
+        .map(|i| {
+            let angle = (i as f64 * 0.5).to_radians();
+            let distance = if angle.cos() > angle.sin() {
+                1000.0 / angle.cos()
+            } else {
+                1000.0 / angle.sin()
+            };
+            (i as f32 * 0.5, distance.round() as u32)
+        })
+        .collect();
+    let scan = LidarScan::from_polar(&points);
+    let features = extract_features(&scan.to_cartesian_points(), &FeatureConfig::default());
+
+    assert_eq!(features.segments.len(), 2, "{:?}", features.segments);
+    let (right, left) = (&features.segments[0], &features.segments[1]);
+    assert!((right.distance - 1000.0).abs() < 2.0);
+    assert!((right.normal - Vector2::new(-1.0, 0.0)).norm() < 0.01);
+    assert!((left.normal - Vector2::new(0.0, -1.0)).norm() < 0.01);
+    assert!(right.covariance[(0, 0)] > 0.0 && right.covariance[(1, 1)] > 0.0);
+
+    assert_eq!(features.corners.len(), 1);
+    let corner = &features.corners[0];
+    assert_eq!(corner.segments, (0, 1));
+    assert!((corner.position - Vector2::new(1000.0, 1000.0)).norm() < 10.0);
+    assert!((corner.angle - std::f64::consts::FRAC_PI_2).abs() < 0.02);
+}
@@ -0,0 +1,182 @@
+//! Queries on the points of a scan in the frame of the car (x forward, y left, angles counterclockwise from straight
+//! ahead in radians), for collision checking and reactive control
+
+use std::f64::consts::{PI, TAU};
+
+use nalgebra::Vector2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// Rectangle around the car relative to its origin, in millimeters
+#[derive(Debug, Clone, Copy)]
+pub struct Footprint {
+    /// Distance from the origin to the front bumper
+    pub front: f64,
+    /// Distance from the origin to the rear bumper
+    pub rear: f64,
+    pub half_width: f64,
+}
+
+/// Closest range in each of `sectors` equal sectors splitting the window from `start` counterclockwise to `end`, None
+/// for sectors without any point. The window wraps around if `end` is below `start`.
+pub fn sector_ranges(
+    points: &[Vector2<f64>],
+    start: f64,
+    end: f64,
+    sectors: usize,
+) -> Vec<Option<f64>> {
+    let mut ranges = vec![None; sectors];
+    let mut width = (end - start).rem_euclid(TAU);
+    if width == 0.0 {
+        width = TAU;
+    }
+    for point in points {
+        let angle = (point.y.atan2(point.x) - start).rem_euclid(TAU);
+        if angle >= width {
+            continue;
+        }
+        let sector = ((angle / width * sectors as f64) as usize).min(sectors - 1);
+        let range = point.norm();
+        if ranges[sector].is_none_or(|closest| range < closest) {
+            ranges[sector] = Some(range);
+        }
+    }
+    ranges
+}
+
+/// Path length the origin of the car drives forward or backward along `curvature` before the footprint touches a
+/// point, None if it never does
+pub fn footprint_clearance(
+    points: &[Vector2<f64>],
+    footprint: &Footprint,
+    curvature: f64,
+    direction: Direction,
+) -> Option<f64> {
+    let edge = match direction {
+        Direction::Forward => footprint.front,
+        Direction::Backward => footprint.rear,
+    };
+    let half_width = footprint.half_width;
+    points
+        .iter()
+        .filter_map(|point| {
+            // driving backwards along an arc is driving forwards along its mirror image, same for turning right
+            let x = if direction == Direction::Forward {
+                point.x
+            } else {
+                -point.x
+            };
+            let y = if curvature >= 0.0 { point.y } else { -point.y };
+            let curvature = curvature.abs();
+            if curvature < 1e-9 {
+                return (x >= edge && y.abs() <= half_width).then_some(x - edge);
+            }
+            let radius = 1.0 / curvature;
+            // the footprint sweeps the ring between its inner side and its outer front corner around (0, radius)
+            let inner = (radius - half_width).max(0.0);
+            let outer = Vector2::new(edge, radius + half_width).norm();
+            let offset = Vector2::new(x, y - radius);
+            let distance = offset.norm();
+            if distance < inner || distance > outer {
+                return None;
+            }
+            // the point of the footprint at this distance from the center furthest ahead of the origin is on the
+            // front edge, or on the inner side for distances the front edge doesn't reach
+            let ahead = edge.min((distance * distance - inner * inner).sqrt());
+            let lead = (ahead / distance).asin();
+            let angle = (offset.y.atan2(offset.x) + PI / 2.0 - lead).rem_euclid(TAU);
+            Some(angle * radius)
+        })
+        .min_by(f64::total_cmp)
+}
+
+#[test]
+fn test_sector_ranges() {
+    let points = [
+        Vector2::new(1000.0, 100.0),
+        Vector2::new(500.0, -100.0),
+        Vector2::new(0.0, 2000.0),
+        Vector2::new(-300.0, 0.0),
+    ];
+    let ranges = sector_ranges(&points, -PI / 4.0, PI / 4.0, 2);
+    assert_eq!(ranges[0], Some(Vector2::new(500.0, -100.0).norm()));
+    assert_eq!(ranges[1], Some(Vector2::new(1000.0, 100.0).norm()));
+    // a window wrapping around behind the car
+    let ranges = sector_ranges(&points, PI / 2.0, -PI / 2.0, 2);
+    assert_eq!(ranges, vec![Some(2000.0), Some(300.0)]);
+}
+
+#[test]
+fn test_clearance() {
+    let points = [Vector2::new(1000.0, 1000.0), Vector2::new(-800.0, 50.0)];
+    // a footprint without length sweeps the band around the arc of the origin, a quarter circle to the left with a
+    // 1000mm radius ends right at the first point
+    let line = Footprint {
+        front: 0.0,
+        rear: 0.0,
+        half_width: 50.0,
+    };
+    let distance = footprint_clearance(&points, &line, 1.0 / 1000.0, Direction::Forward).unwrap();
+    assert!((distance - 1000.0 * PI / 2.0).abs() < 1e-6);
+    assert_eq!(
+        footprint_clearance(&points, &line, -1.0 / 1000.0, Direction::Forward),
+        None
+    );
+    assert_eq!(
+        footprint_clearance(&points, &line, 0.0, Direction::Backward),
+        Some(800.0)
+    );
+
+    let footprint = Footprint {
+        front: 300.0,
+        rear: 100.0,
+        half_width: 150.0,
+    };
+    assert_eq!(
+        footprint_clearance(&points, &footprint, 0.0, Direction::Forward),
+        None
+    );
+    assert_eq!(
+        footprint_clearance(&points, &footprint, 0.0, Direction::Backward),
+        Some(700.0)
+    );
+    // a point off to the side is in the way of a left turn, the front edge reaches it before the origin has turned a
+    // quarter circle
+    let distance = footprint_clearance(
+        &[Vector2::new(1100.0, 1000.0)],
+        &footprint,
+        1.0 / 1000.0,
+        Direction::Forward,
+    )
+    .unwrap();
+    assert!((distance - 1000.0 * (PI / 2.0 - (300.0f64 / 1100.0).asin())).abs() < 1e-6);
+
+    // in a tight left turn around (0, 500) the outer front corner sweeps out to 716mm from the center, past the outer
+    // side at 650mm
+    let corner = [Vector2::new(0.0, 1200.0)];
+    let distance =
+        footprint_clearance(&corner, &footprint, 1.0 / 500.0, Direction::Forward).unwrap();
+    assert!((distance - 500.0 * (PI - (300.0f64 / 700.0).asin())).abs() < 1e-6);
+    assert_eq!(
+        footprint_clearance(
+            &[Vector2::new(0.0, 1230.0)],
+            &footprint,
+            1.0 / 500.0,
+            Direction::Forward
+        ),
+        None
+    );
+    // the same turn to the right and backwards, where the shorter rear corner only sweeps out to 658mm
+    let distance = footprint_clearance(
+        &[Vector2::new(0.0, -1155.0)],
+        &footprint,
+        -1.0 / 500.0,
+        Direction::Backward,
+    )
+    .unwrap();
+    assert!((distance - 500.0 * (PI - (100.0f64 / 655.0).asin())).abs() < 1e-6);
+}
@@ -28,162 +28,752 @@
 #define SL_LIDAR_CMD_GET_ACC_BOARD_FLAG     0xFF
  */
 
+use std::collections::VecDeque;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 
+use futures::Stream;
+
 use tokio_serial::SerialPort;
 use tokio_serial::SerialStream;
 
-use nalgebra::Vector2;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use nalgebra::{Isometry2, Point2, Vector2};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+
+use crate::free_space::sector_ranges;
+use crate::lidar_recording::{LidarRecorder, LidarReplay};
+use crate::pose_graph::PositionDiff;
+
+/// Length in bytes of a measurement node sent in response to a standard scan request
+const SCAN_NODE_LEN: usize = 5;
+/// Length in bytes of a legacy or dense capsule sent in response to an express scan request
+const CAPSULE_LEN: usize = 84;
+/// Length in bytes of an ultra capsule sent in response to an express scan request
+const ULTRA_CAPSULE_LEN: usize = 132;
+/// Express capsules don't carry a quality per point, so valid points get the same value the Slamtec SDK reports for them
+const EXPRESS_QUALITY: u8 = 0x2F;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanMode {
+    /// The legacy 0x20 scan, which sends a 5 byte node with its own quality for every point. Works on any firmware.
+    Standard,
+    /// The 0x82 express scan. The working mode and capsule format are picked from the scan modes the lidar reports
+    /// through GET_LIDAR_CONF, falling back to legacy capsules on firmware older than 1.24.
+    Express,
+}
+
+/// The format of the measurements streamed after a scan request, named after the answer type in the response descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanFormat {
+    /// 5 byte nodes of a standard scan
+    Standard,
+    /// 84 byte capsules of 16 cabins with two distances and angle compensations each
+    Capsuled,
+    /// 132 byte capsules of 32 cabins with three variable bit scaled distances each
+    UltraCapsuled,
+    /// 84 byte capsules of 40 distances
+    DenseCapsuled,
+}
+
+impl ScanFormat {
+    pub fn from_answer_type(answer_type: u8) -> Option<Self> {
+        match answer_type {
+            0x81 => Some(ScanFormat::Standard),
+            0x82 => Some(ScanFormat::Capsuled),
+            0x84 => Some(ScanFormat::UltraCapsuled),
+            0x85 => Some(ScanFormat::DenseCapsuled),
+            _ => None,
+        }
+    }
+
+    pub fn answer_type(&self) -> u8 {
+        match self {
+            ScanFormat::Standard => 0x81,
+            ScanFormat::Capsuled => 0x82,
+            ScanFormat::UltraCapsuled => 0x84,
+            ScanFormat::DenseCapsuled => 0x85,
+        }
+    }
+
+    /// How many measurements a single packet of this format carries
+    fn points_per_packet(&self) -> u32 {
+        match self {
+            ScanFormat::Standard => 1,
+            ScanFormat::Capsuled => 32,
+            ScanFormat::UltraCapsuled => 96,
+            ScanFormat::DenseCapsuled => 40,
+        }
+    }
+
+    fn packet_len(&self) -> usize {
+        match self {
+            ScanFormat::Standard => SCAN_NODE_LEN,
+            ScanFormat::Capsuled | ScanFormat::DenseCapsuled => CAPSULE_LEN,
+            ScanFormat::UltraCapsuled => ULTRA_CAPSULE_LEN,
+        }
+    }
+
+    /// The descriptor the lidar answers the scan request with before it starts streaming
+    fn expected_descriptor(&self) -> ResponseDescriptor {
+        ResponseDescriptor {
+            length: self.packet_len() as u32,
+            send_mode: SendMode::Multiple,
+            data_type: self.answer_type(),
+        }
+    }
+}
+
+/// A scan mode as reported by GET_LIDAR_CONF
+#[derive(Debug, Clone)]
+pub struct ScanModeInfo {
+    pub id: u16,
+    pub name: String,
+    /// Time between two samples in microseconds
+    pub us_per_sample: f32,
+    /// Maximum measurable distance in meters
+    pub max_distance: f32,
+    /// None if the mode streams a format this engine can't decode
+    pub format: Option<ScanFormat>,
+}
+
+impl ScanModeInfo {
+    fn is_express(&self) -> bool {
+        self.format
+            .is_some_and(|format| format != ScanFormat::Standard)
+    }
+}
+
+/// Pick the express mode with the given name, or the lidar's typical mode, or the fastest express mode otherwise
+fn pick_express_mode<'a>(
+    modes: &'a [ScanModeInfo],
+    typical: u16,
+    name: Option<&str>,
+) -> Option<&'a ScanModeInfo> {
+    let mut express_modes = modes.iter().filter(|mode| mode.is_express());
+    if let Some(name) = name {
+        return express_modes.find(|mode| mode.name == name);
+    }
+    if let Some(mode) = modes
+        .iter()
+        .find(|mode| mode.id == typical && mode.is_express())
+    {
+        return Some(mode);
+    }
+    express_modes.min_by(|a, b| a.us_per_sample.total_cmp(&b.us_per_sample))
+}
+
+#[derive(Debug, Clone)]
+pub struct LidarConfig {
+    pub scan_mode: ScanMode,
+    /// Name of the express scan mode to use, like "Sensitivity" or "Boost". The lidar's typical mode is used if this is
+    /// None.
+    pub express_mode_name: Option<String>,
+    /// Rotation speed to set before scanning, None leaves the lidar at its default speed
+    pub motor_speed: Option<MotorSpeed>,
+    /// How many times to try starting a scan before giving up, the lidar is reset between attempts
+    pub init_attempts: u32,
+    /// How long to wait for the lidar to answer a request
+    pub response_timeout: Duration,
+    /// How long the lidar needs after a stop request before it is quiet and ready for the next request
+    pub stop_time: Duration,
+    /// How long the lidar needs to reboot after a reset request
+    pub reset_time: Duration,
+    /// How many completed scans the engine keeps around
+    pub scan_history: usize,
+    /// Where the lidar sits on the car
+    pub mount: LidarMount,
+}
+
+impl Default for LidarConfig {
+    fn default() -> Self {
+        Self {
+            scan_mode: ScanMode::Express,
+            express_mode_name: None,
+            motor_speed: None,
+            init_attempts: 5,
+            response_timeout: Duration::from_millis(1000),
+            stop_time: Duration::from_millis(800),
+            reset_time: Duration::from_millis(2000),
+            scan_history: 10,
+            mount: LidarMount::default(),
+        }
+    }
+}
+
+/// Pose of the lidar on the car and the parts of its view blocked by the car itself
+#[derive(Debug, Clone, Default)]
+pub struct LidarMount {
+    /// Position of the lidar in millimeters from the origin of the car, x forward and y to the left
+    pub x: f64,
+    pub y: f64,
+    /// Angle between the lidar's 0 degrees and straight ahead in radians, counterclockwise
+    pub yaw: f64,
+    /// Points with an angle inside any of these ranges hit the car and are dropped
+    pub masks: Vec<AngleMask>,
+}
+
+impl LidarMount {
+    /// Transform from the lidar's frame to the car's frame
+    pub fn sensor_to_base(&self) -> Isometry2<f64> {
+        Isometry2::new(Vector2::new(self.x, self.y), self.yaw)
+    }
+
+    pub fn is_masked(&self, point: &LidarPoint) -> bool {
+        let angle = point.get_angle_degrees();
+        self.masks.iter().any(|mask| mask.contains(angle))
+    }
+
+    pub fn load<Q: AsRef<Path>>(path: Q) -> Result<Self, LidarMountError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse a mount file, one entry per line with `#` starting a comment. `x` and `y` are in millimeters, `yaw` in
+    /// degrees, and every `mask <start> <end>` line adds a masked range of lidar degrees. Left out entries are zero.
+    ///
+    /// ```text
+    /// x 0
+    /// y 0
+    /// yaw 180
+    /// mask 350 10
+    /// ```
+    pub fn parse(text: &str) -> Result<Self, LidarMountError> {
+        let mut mount = LidarMount::default();
+        for (index, line) in text.lines().enumerate() {
+            let content = line.split('#').next().unwrap().trim();
+            if content.is_empty() {
+                continue;
+            }
+            let parse_error = || LidarMountError::Parse {
+                line: index + 1,
+                text: line.to_string(),
+            };
+            let words: Vec<&str> = content.split_whitespace().collect();
+            match words[..] {
+                ["x", value] => mount.x = value.parse().map_err(|_| parse_error())?,
+                ["y", value] => mount.y = value.parse().map_err(|_| parse_error())?,
+                ["yaw", value] => {
+                    let degrees: f64 = value.parse().map_err(|_| parse_error())?;
+                    mount.yaw = degrees.to_radians();
+                }
+                ["mask", start, end] => mount.masks.push(AngleMask {
+                    start: start.parse().map_err(|_| parse_error())?,
+                    end: end.parse().map_err(|_| parse_error())?,
+                }),
+                _ => return Err(parse_error()),
+            }
+        }
+        Ok(mount)
+    }
+}
+
+#[derive(Debug)]
+pub enum LidarMountError {
+    Io(std::io::Error),
+    /// A line that isn't a known entry, numbered from 1
+    Parse {
+        line: usize,
+        text: String,
+    },
+}
+
+impl std::fmt::Display for LidarMountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LidarMountError::Io(err) => write!(f, "failed to read lidar mount: {}", err),
+            LidarMountError::Parse { line, text } => {
+                write!(f, "can't parse line {}: {:?}", line, text)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LidarMountError {}
 
-pub struct LidarEngine {
-    pub port: SerialStream,
+impl From<std::io::Error> for LidarMountError {
+    fn from(err: std::io::Error) -> Self {
+        LidarMountError::Io(err)
+    }
+}
+
+/// A range of lidar angles in degrees, going from `start` up to `end` and wrapping past 360 if `end` is below `start`
+#[derive(Debug, Clone, Copy)]
+pub struct AngleMask {
+    pub start: f32,
+    pub end: f32,
+}
+
+impl AngleMask {
+    fn contains(&self, angle: f32) -> bool {
+        if self.start <= self.end {
+            self.start <= angle && angle <= self.end
+        } else {
+            self.start <= angle || angle <= self.end
+        }
+    }
+}
+
+/// How much each newly completed scan moves the measured scan frequency
+const SCAN_FREQUENCY_SMOOTHING: f32 = 0.2;
+
+/// Where the engine reads the lidar's bytes from
+pub trait LidarSource: AsyncRead + Unpin {
+    /// When the bytes returned by the last read arrived
+    fn read_time(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl LidarSource for SerialStream {}
+
+impl LidarSource for LidarReplay {
+    fn read_time(&self) -> Instant {
+        LidarReplay::read_time(self)
+    }
+}
+
+pub struct LidarEngine<P = SerialStream> {
+    pub port: P,
+    pub config: LidarConfig,
+    /// The format the lidar is streaming in, decided on init
+    pub format: ScanFormat,
+    /// The scan mode picked on init, None for a standard scan or firmware without GET_LIDAR_CONF
+    pub scan_mode_info: Option<ScanModeInfo>,
+    /// Sample durations reported by the lidar on init, None on firmware older than 1.17
+    pub sample_rate: Option<SampleRate>,
+    /// The last two capsules, the older one can be decoded using the start angle of the newer one
     pub scan_packets: Vec<ScanPacket>,
-    pub scans: Vec<LidarScan>,
+    /// The most recent completed scans, oldest first, followed by the scan in progress
+    pub scans: VecDeque<LidarScan>,
+    /// How many of the completed scans haven't been yielded by the stream yet
+    unyielded_scans: usize,
+    /// Bytes read from the port which haven't been decoded into a packet yet
+    buffer: Vec<u8>,
+    /// When the last bytes in the buffer arrived
+    read_time: Instant,
+    /// Number of packets thrown away because of a bad sync or checksum
+    pub dropped_packets: u32,
+    /// When the current scan started
+    scan_started: Option<Instant>,
+    /// Smoothed rotation frequency of the lidar in Hz, measured from how long each scan takes
+    scan_frequency: Option<f32>,
+    /// Writes every decoded packet to a log file when set
+    pub recorder: Option<LidarRecorder>,
 }
 
-impl LidarEngine {
-    pub async fn new(port: SerialStream) -> Self {
-        let mut engine = Self {
-            port,
-            scan_packets: Vec::new(),
-            scans: Vec::new(),
-        };
-        engine.init().await;
-        engine
+impl LidarEngine<SerialStream> {
+    pub async fn new(port: SerialStream, config: LidarConfig) -> Result<Self, LidarInitError> {
+        let mut engine = Self::with_port(port, config);
+        engine.init().await?;
+        Ok(engine)
     }
 
-    async fn init(&mut self) {
+    /// Try to start scanning, resetting the lidar after every failed attempt
+    async fn init(&mut self) -> Result<(), LidarInitError> {
         println!("Initializing Lidar");
-        let tries = 5; // TODO constant
-        for i in 0..tries {
-            LidarRequest::Stop.write(&mut self.port).await.unwrap();
-            // wait
-            std::thread::sleep(Duration::from_millis(800));
-            // clear buffer
-            self.port.clear(tokio_serial::ClearBuffer::Input).unwrap();
-            self.port
-                .write(&[0xa5, 0x82, 0x05, 0x03, 0x00, 0x00, 0x00, 0x00, 0x21])
-                .await
-                .unwrap();
-            std::thread::sleep(Duration::from_millis(800));
-            let mut buf = [0; 7];
-            self.port.read_exact(&mut buf).await.unwrap();
-            if buf == [0xa5, 0x5a, 0x84, 0x00, 0x00, 0x40, 0x84] {
-                println!("Lidar initialized");
-                break;
-            } else {
+        let attempts = self.config.init_attempts.max(1);
+        let mut last_error = None;
+        for attempt in 1..=attempts {
+            match self.start_scan().await {
+                Ok(()) => {
+                    println!("Lidar initialized streaming {:?}", self.format);
+                    return Ok(());
+                }
+                Err(err) => {
+                    println!(
+                        "Lidar initialization attempt {} of {} failed: {}",
+                        attempt, attempts, err
+                    );
+                    last_error = Some(err);
+                }
+            }
+            if attempt < attempts {
+                if let Err(err) = LidarRequest::Reset.write(&mut self.port).await {
+                    println!("Failed to reset lidar: {}", err);
+                }
+                tokio::time::sleep(self.config.reset_time).await;
+            }
+        }
+        Err(match last_error.unwrap() {
+            LidarError::Unhealthy { error_code } => LidarInitError::Unhealthy { error_code },
+            last_error => LidarInitError::Failed {
+                attempts,
+                last_error,
+            },
+        })
+    }
+
+    /// Stop whatever the lidar is doing, check its health and start scanning in the configured mode
+    async fn start_scan(&mut self) -> Result<(), LidarError> {
+        LidarRequest::Stop.write(&mut self.port).await?;
+        tokio::time::sleep(self.config.stop_time).await;
+        self.port.clear(tokio_serial::ClearBuffer::Input)?;
+        self.check_device().await?;
+        self.sample_rate = match self.request(LidarRequest::GetSampleRate).await {
+            Ok(LidarResponse::SampleRate(sample_rate)) => {
                 println!(
-                    "Lidar initialization failed, retrying {} more times",
-                    tries - i - 1
+                    "Lidar sample duration {}us standard, {}us express",
+                    sample_rate.standard_us, sample_rate.express_us
                 );
+                Some(sample_rate)
+            }
+            _ => {
+                println!("Lidar sample rate unavailable");
+                None
             }
+        };
+        let request = self.select_scan_mode().await?;
+        if let Some(motor_speed) = self.config.motor_speed {
+            self.set_motor_speed(motor_speed).await?;
         }
+        request.write(&mut self.port).await?;
+        let descriptor = tokio::time::timeout(
+            self.config.response_timeout,
+            ResponseDescriptor::read(&mut self.port),
+        )
+        .await
+        .map_err(|_| LidarError::Timeout)??;
+        if descriptor != self.format.expected_descriptor() {
+            return Err(LidarError::UnexpectedResponse(descriptor));
+        }
+        Ok(())
     }
 
-    /// See if there are new scan packets and process them accordingly in order to optionally get a new scan
-    pub async fn poll(&mut self) -> Option<&LidarScan> {
-        if self.port.bytes_to_read().unwrap() >= 132 {
-            let scan_count = self.scans.len();
-            let mut buffer = [0; 132];
-            self.port.read_exact(&mut buffer).await.unwrap();
-            self.scan_packets.push(ScanPacket::from_buffer(&buffer));
-            if self.scan_packets.len() > 1 {
-                for i in 0..32 {
-                    let mut dist_q2 = [0; 3];
+    /// Log the model, firmware and serial number of the lidar and make sure it isn't reporting an error
+    async fn check_device(&mut self) -> Result<(), LidarError> {
+        if let LidarResponse::DeviceInfo {
+            model,
+            firmware_minor,
+            firmware_major,
+            hardware,
+            serial,
+        } = self.request(LidarRequest::GetDeviceInfo).await?
+        {
+            let serial: String = serial.iter().map(|byte| format!("{:02X}", byte)).collect();
+            println!(
+                "Lidar model {:#04x}, firmware {}.{:02}, hardware {}, serial {}",
+                model, firmware_major, firmware_minor, hardware, serial
+            );
+        }
+        if let LidarResponse::DeviceHealth { status, error_code } =
+            self.request(LidarRequest::GetDeviceHealth).await?
+        {
+            match status {
+                HealthStatus::Good => println!("Lidar health good"),
+                HealthStatus::Warning => {
+                    println!("Lidar health warning, error code {:#06x}", error_code)
+                }
+                HealthStatus::Error => return Err(LidarError::Unhealthy { error_code }),
+            }
+        }
+        Ok(())
+    }
 
-                    let combined_x3 =
-                        self.scan_packets[self.scan_packets.len() - 2].ultra_cabins[i];
+    /// Decide which scan request to send and which format the lidar will answer it with
+    async fn select_scan_mode(&mut self) -> Result<LidarRequest, LidarError> {
+        self.scan_mode_info = None;
+        if self.config.scan_mode == ScanMode::Standard {
+            self.format = ScanFormat::Standard;
+            return Ok(LidarRequest::Scan);
+        }
+        let (modes, typical) = match self.get_scan_modes().await {
+            Ok(modes) => modes,
+            Err(err) => {
+                println!(
+                    "Lidar scan modes unavailable ({}), falling back to legacy express capsules",
+                    err
+                );
+                self.format = ScanFormat::Capsuled;
+                return Ok(LidarRequest::ExpressScan { working_mode: 0 });
+            }
+        };
+        for mode in &modes {
+            println!(
+                "Lidar scan mode {} \"{}\": {}us per sample, {}m max, {:?}",
+                mode.id, mode.name, mode.us_per_sample, mode.max_distance, mode.format
+            );
+        }
+        let mode = pick_express_mode(&modes, typical, self.config.express_mode_name.as_deref())
+            .ok_or(LidarError::NoScanMode)?
+            .clone();
+        println!("Using lidar scan mode \"{}\"", mode.name);
+        self.format = mode.format.unwrap();
+        let request = LidarRequest::ExpressScan {
+            working_mode: mode.id as u8,
+        };
+        self.scan_mode_info = Some(mode);
+        Ok(request)
+    }
 
-                    // unpack
-                    let dist_major1 = combined_x3 & 0xFFF;
-                    let mut dist_predict1 = ((combined_x3 as i32) << 10) >> 22;
-                    let mut dist_predict2 = (combined_x3 as i32) >> 22;
+    /// Query every scan mode the lidar supports along with the id of its typical mode
+    async fn get_scan_modes(&mut self) -> Result<(Vec<ScanModeInfo>, u16), LidarError> {
+        let count = conf_u16(&self.get_conf(LidarConf::ModeCount).await?)?;
+        let typical = conf_u16(&self.get_conf(LidarConf::TypicalMode).await?)?;
+        let mut modes = Vec::with_capacity(count as usize);
+        for id in 0..count {
+            let us_per_sample = conf_u32(&self.get_conf(LidarConf::UsPerSample(id)).await?)?;
+            let max_distance = conf_u32(&self.get_conf(LidarConf::MaxDistance(id)).await?)?;
+            let answer_type = self.get_conf(LidarConf::AnswerType(id)).await?;
+            let name = self.get_conf(LidarConf::ModeName(id)).await?;
+            modes.push(ScanModeInfo {
+                id,
+                name: String::from_utf8_lossy(&name)
+                    .trim_end_matches('\0')
+                    .to_string(),
+                // both are fixed point with 8 fractional bits
+                us_per_sample: us_per_sample as f32 / 256.0,
+                max_distance: max_distance as f32 / 256.0,
+                format: answer_type
+                    .first()
+                    .and_then(|&t| ScanFormat::from_answer_type(t)),
+            });
+        }
+        Ok((modes, typical))
+    }
 
-                    let dist_major2 = if i == 31 {
-                        &self.scan_packets[self.scan_packets.len() - 1].ultra_cabins[0] & 0xFFF
-                    } else {
-                        &self.scan_packets[self.scan_packets.len() - 2].ultra_cabins[i + 1] & 0xFFF
-                    };
+    /// Read a configuration entry of the lidar, returning the data after the echoed configuration type
+    async fn get_conf(&mut self, conf: LidarConf) -> Result<Vec<u8>, LidarError> {
+        match self.request(LidarRequest::GetLidarConf(conf)).await? {
+            LidarResponse::LidarConf { conf_type, data } if conf_type == conf.conf_type() => {
+                Ok(data)
+            }
+            _ => Err(LidarError::InvalidConf(conf.conf_type())),
+        }
+    }
 
-                    let mut scale_level1 = 0;
-                    let mut scale_level2 = 0;
+    /// Change the rotation speed of the lidar, which can be done while it is scanning
+    pub async fn set_motor_speed(&mut self, motor_speed: MotorSpeed) -> Result<(), LidarError> {
+        println!("Setting lidar motor speed to {:?}", motor_speed);
+        motor_speed.request().write(&mut self.port).await?;
+        Ok(())
+    }
 
-                    let dist_major1 = varbitscale_decode(dist_major1, &mut scale_level1);
-                    let dist_major2 = varbitscale_decode(dist_major2, &mut scale_level2);
+    /// Send a request and wait for its response
+    async fn request(&mut self, request: LidarRequest) -> Result<LidarResponse, LidarError> {
+        request.write(&mut self.port).await?;
+        tokio::time::timeout(
+            self.config.response_timeout,
+            LidarResponse::read(&mut self.port),
+        )
+        .await
+        .map_err(|_| LidarError::Timeout)?
+    }
+}
 
-                    let mut dist_base1 = dist_major1;
-                    let dist_base2 = dist_major2;
+impl LidarEngine<LidarReplay> {
+    /// Decode a recorded log instead of a live lidar
+    pub fn from_replay(replay: LidarReplay, config: LidarConfig) -> Self {
+        let format = replay.format;
+        let us_per_sample = replay.us_per_sample;
+        let mut engine = Self::with_port(replay, config);
+        engine.format = format;
+        engine.scan_mode_info = us_per_sample.map(|us_per_sample| ScanModeInfo {
+            id: 0,
+            name: "Replay".to_string(),
+            us_per_sample,
+            max_distance: 0.0,
+            format: Some(format),
+        });
+        engine
+    }
+}
 
-                    if dist_major1 == 0 && dist_major2 != 0 {
-                        dist_base1 = dist_major2;
-                        scale_level1 = scale_level2;
-                    }
+impl<P> LidarEngine<P> {
+    /// Start writing everything read from the lidar to a log file, call once the lidar is scanning
+    pub fn record_to(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        self.recorder = Some(LidarRecorder::create(
+            path,
+            self.format,
+            self.sample_duration(),
+        )?);
+        Ok(())
+    }
 
-                    dist_q2[0] = dist_major1 << 2;
-                    if dist_predict1 as u32 == 0xFFFFFE00 || dist_predict1 == 0x1FF {
-                        dist_q2[1] = 0
-                    } else {
-                        dist_predict1 <<= scale_level1;
-                        dist_q2[1] = ((dist_base1 as i32 + dist_predict1) << 2) as u32;
-                    }
+    fn with_port(port: P, config: LidarConfig) -> Self {
+        LidarEngine {
+            port,
+            config,
+            format: ScanFormat::Standard,
+            scan_mode_info: None,
+            sample_rate: None,
+            scan_packets: Vec::new(),
+            scans: VecDeque::new(),
+            unyielded_scans: 0,
+            buffer: Vec::new(),
+            read_time: Instant::now(),
+            dropped_packets: 0,
+            scan_started: None,
+            scan_frequency: None,
+            recorder: None,
+        }
+    }
+
+    /// Time between two samples in the mode the lidar is scanning in, if the lidar reported it
+    pub fn sample_duration(&self) -> Option<Duration> {
+        let us_per_sample = match (&self.scan_mode_info, self.sample_rate) {
+            (Some(mode), _) => mode.us_per_sample,
+            (None, Some(sample_rate)) if self.format == ScanFormat::Standard => {
+                sample_rate.standard_us as f32
+            }
+            (None, Some(sample_rate)) => sample_rate.express_us as f32,
+            (None, None) => return None,
+        };
+        Some(Duration::from_secs_f32(us_per_sample / 1_000_000.0))
+    }
 
-                    if dist_predict2 as u32 == 0xFFFFFE00 || dist_predict2 == 0x1FF {
-                        dist_q2[2] = 0
-                    } else {
-                        dist_predict2 <<= scale_level2;
-                        dist_q2[2] = ((dist_base2 as i32 + dist_predict2) << 2) as u32;
+    /// Rotation frequency of the lidar in Hz measured from the completed scans, None until two scans have completed
+    pub fn scan_frequency_hz(&self) -> Option<f32> {
+        self.scan_frequency
+    }
+
+    /// Decode every complete packet in the buffer
+    fn decode(&mut self) -> Result<(), LidarError> {
+        match self.format {
+            ScanFormat::Standard => self.decode_nodes(self.read_time),
+            _ => self.decode_capsules(self.read_time),
+        }
+    }
+
+    /// The oldest completed scan which hasn't been yielded by the stream yet
+    fn take_completed_scan(&mut self) -> Option<LidarScan> {
+        if self.unyielded_scans == 0 {
+            return None;
+        }
+        let scan = self.scans[self.scans.len() - 1 - self.unyielded_scans].clone();
+        self.unyielded_scans -= 1;
+        Some(scan)
+    }
+
+    /// Decode every complete standard scan node in the buffer
+    fn decode_nodes(&mut self, read_time: Instant) -> Result<(), LidarError> {
+        while self.buffer.len() >= SCAN_NODE_LEN {
+            let point = LidarPoint::from_scan_node(
+                self.buffer[..SCAN_NODE_LEN].try_into().unwrap(),
+                self.packet_timestamp(read_time),
+            );
+            match point {
+                Ok(point) => {
+                    self.buffer.drain(..SCAN_NODE_LEN);
+                    // reject 0 distance points, they represent points which are either too far or too close to be detected
+                    if point.distance_mm != 0 {
+                        self.add_point(point);
                     }
+                }
+                Err(err) => {
+                    self.dropped_packets += 1;
+                    resync(&mut self.buffer, is_node_start);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
 
-                    let start_angle_q6 =
-                        self.scan_packets[self.scan_packets.len() - 2].start_angle_q6 as i32;
-                    let angle_diff_q6 = (self.scan_packets[self.scan_packets.len() - 1]
-                        .start_angle_q6 as i32
-                        - start_angle_q6 as i32)
-                        .rem_euclid(360 * 64);
-
-                    for j in 0..3 {
-                        let point = LidarPoint {
-                            angle_q6: (start_angle_q6 as u16
-                                + (angle_diff_q6 as f64 * (i as f64 / 32.0 + j as f64 / 96.0))
-                                    as u16)
-                                % (360 * 64),
-                            distance_q0: dist_q2[j],
-                            index: j as u8,
-                        };
-
-                        // reject 0 distance points, they represent points which are either too far or too close to be detected
-                        if point.distance_q0 != 0 {
-                            self.add_point(point);
-                        }
+    /// Decode every complete capsule in the buffer
+    fn decode_capsules(&mut self, read_time: Instant) -> Result<(), LidarError> {
+        let packet_len = self.format.packet_len();
+        while self.buffer.len() >= packet_len {
+            let timestamp = self.packet_timestamp(read_time);
+            match ScanPacket::from_buffer(&self.buffer[..packet_len], self.format, timestamp) {
+                Ok(packet) => {
+                    self.buffer.drain(..packet_len);
+                    if self.scan_packets.len() == 2 {
+                        self.scan_packets.remove(0);
                     }
+                    self.scan_packets.push(packet);
+                }
+                Err(err) => {
+                    self.dropped_packets += 1;
+                    // the cabins of the last good packet are decoded using the start angle of the one after it, which is gone now
+                    self.scan_packets.clear();
+                    resync(&mut self.buffer, is_capsule_start);
+                    return Err(err);
                 }
             }
-            if self.scans.len() >= 2 && scan_count != self.scans.len() {
-                return Some(&self.scans[self.scans.len() - 2]);
+            if self.scan_packets.len() > 1 {
+                let previous = &self.scan_packets[self.scan_packets.len() - 2];
+                let current = &self.scan_packets[self.scan_packets.len() - 1];
+                for point in previous.points_until(current) {
+                    // reject 0 distance points, they represent points which are either too far or too close to be detected
+                    if point.distance_mm != 0 {
+                        self.add_point(point);
+                    }
+                }
             }
         }
-        None
+        Ok(())
+    }
+
+    /// Estimate when the packet at the front of the buffer was sent, assuming the lidar streams packets evenly and the
+    /// last one in the buffer arrived at `read_time`
+    fn packet_timestamp(&self, read_time: Instant) -> Instant {
+        let packets_after = (self.buffer.len() / self.format.packet_len()).saturating_sub(1);
+        let packet_duration =
+            self.sample_duration().unwrap_or_default() * self.format.points_per_packet();
+        read_time
+            .checked_sub(packet_duration * packets_after as u32)
+            .unwrap_or(read_time)
+    }
+
+    /// Write out the buffered part of the recording once per scan, so stopping the car loses at most the last scan
+    fn flush_recording(&mut self) {
+        let Some(recorder) = &mut self.recorder else {
+            return;
+        };
+        if let Err(err) = recorder.flush() {
+            println!("Stopping lidar recording: {}", err);
+            self.recorder = None;
+        }
+    }
+
+    /// Write the bytes of the last read to the recording as they came, if there is one. Corrupt packets and the bytes
+    /// skipped to resync are recorded too, so a replay goes through the same decoding.
+    fn record_read(&mut self, bytes: &[u8]) {
+        let read_time = self.read_time;
+        let Some(recorder) = &mut self.recorder else {
+            return;
+        };
+        if let Err(err) = recorder.record(read_time, bytes) {
+            println!("Stopping lidar recording: {}", err);
+            self.recorder = None;
+        }
     }
 
     fn add_point(&mut self, point: LidarPoint) {
-        if self.scans.is_empty() {
-            self.scans.push(LidarScan { points: Vec::new() });
+        if self.config.mount.is_masked(&point) {
+            return;
         }
-        let current_scan = self.scans.last_mut().unwrap();
-        if current_scan.points.is_empty() {
-            current_scan.points.push(point);
-        } else {
-            // wrap once the scan is complete
-            let last_point = current_scan.points.last().unwrap();
-            if last_point.angle_q6 > point.angle_q6 {
-                self.scans.push(LidarScan {
-                    points: vec![point],
-                });
-            } else {
-                current_scan.points.push(point);
+        let sensor_to_base = self.config.mount.sensor_to_base();
+        let Some(current_scan) = self.scans.back_mut() else {
+            self.scans.push_back(LidarScan::new(point, sensor_to_base));
+            return;
+        };
+        // wrap once the scan is complete
+        let last_point = current_scan.points.last().unwrap();
+        if last_point.angle_q6 > point.angle_q6 {
+            self.scans.push_back(LidarScan::new(point, sensor_to_base));
+            // keep the scan in progress on top of the history
+            while self.scans.len() > self.config.scan_history.max(1) + 1 {
+                self.scans.pop_front();
             }
+            self.unyielded_scans = (self.unyielded_scans + 1).min(self.scans.len() - 1);
+            self.measure_scan_frequency(point.timestamp);
+        } else {
+            current_scan.points.push(point);
+            current_scan.end_time = point.timestamp;
+        }
+    }
+
+    fn measure_scan_frequency(&mut self, now: Instant) {
+        if let Some(scan_started) = self.scan_started {
+            let frequency = 1.0 / now.saturating_duration_since(scan_started).as_secs_f32();
+            self.scan_frequency = Some(match self.scan_frequency {
+                Some(smoothed) => smoothed + (frequency - smoothed) * SCAN_FREQUENCY_SMOOTHING,
+                None => frequency,
+            });
         }
+        self.scan_started = Some(now);
     }
 
     pub fn get_most_recent_scan(&self) -> Option<&LidarScan> {
@@ -195,14 +785,68 @@ impl LidarEngine {
     }
 }
 
+/// Yields every scan once the lidar completes a revolution, waiting for the port in between
+impl<P: LidarSource> Stream for LidarEngine<P> {
+    type Item = Result<LidarScan, LidarError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let engine = self.get_mut();
+        loop {
+            if let Some(scan) = engine.take_completed_scan() {
+                engine.flush_recording();
+                return Poll::Ready(Some(Ok(scan)));
+            }
+            // a bad packet stops decoding early, the rest of the buffer is decoded on the next poll
+            if engine.buffer.len() >= engine.format.packet_len() {
+                if let Err(err) = engine.decode() {
+                    return Poll::Ready(Some(Err(err)));
+                }
+                continue;
+            }
+            let mut bytes = [0; 1024];
+            let mut read_buf = ReadBuf::new(&mut bytes);
+            match Pin::new(&mut engine.port).poll_read(cx, &mut read_buf) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(err)) => return Poll::Ready(Some(Err(err.into()))),
+                Poll::Ready(Ok(())) if read_buf.filled().is_empty() => return Poll::Ready(None),
+                Poll::Ready(Ok(())) => {
+                    engine.read_time = engine.port.read_time();
+                    engine.record_read(read_buf.filled());
+                    engine.buffer.extend_from_slice(read_buf.filled());
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct LidarPoint {
     pub angle_q6: u16,
-    pub distance_q0: u32,
+    /// Distance in millimeters
+    pub distance_mm: u32,
+    /// Signal strength of the reflection between 0 and 63
+    pub quality: u8,
     pub index: u8,
+    /// When the point was measured, interpolated between the arrival of the packets around it
+    pub timestamp: Instant,
 }
 
 impl LidarPoint {
+    /// Decode a measurement node of a standard scan:
+    /// quality (6 bits), inverted start flag, start flag | angle_q6 (15 bits), check bit | distance_q2 (16 bits)
+    fn from_scan_node(bytes: &[u8; SCAN_NODE_LEN], timestamp: Instant) -> Result<Self, LidarError> {
+        if !is_node_start(bytes) {
+            return Err(LidarError::InvalidNode(*bytes));
+        }
+        Ok(LidarPoint {
+            angle_q6: u16::from_le_bytes([bytes[1], bytes[2]]) >> 1,
+            distance_mm: u16::from_le_bytes([bytes[3], bytes[4]]) as u32 >> 2,
+            quality: bytes[0] >> 2,
+            index: 0,
+            timestamp,
+        })
+    }
+
     pub fn get_angle_degrees(&self) -> f32 {
         self.angle_q6 as f32 / 64.0
     }
@@ -212,11 +856,12 @@ impl LidarPoint {
     pub fn get_angle_rad_f64(&self) -> f64 {
         self.angle_q6 as f64 * std::f64::consts::PI / 180.0 / 64.0
     }
+    /// Position of the point in the lidar's frame
     pub fn to_cartesian(&self) -> Vector2<f64> {
         let angle = self.get_angle_rad_f64();
         Vector2::new(
-            self.distance_q0 as f64 * angle.cos(),
-            self.distance_q0 as f64 * angle.sin(),
+            self.distance_mm as f64 * angle.cos(),
+            self.distance_mm as f64 * angle.sin(),
         )
     }
 }
@@ -225,20 +870,45 @@ pub struct ScanPacket {
     pub timestamp: Instant,
     pub start_bit: bool,
     pub start_angle_q6: u16,
-    pub ultra_cabins: [u32; 32],
+    pub cabins: Cabins,
+}
+
+/// The measurements of a capsule, in one of the layouts of [`ScanFormat`]
+pub enum Cabins {
+    Capsuled([CapsuleCabin; 16]),
+    Ultra([u32; 32]),
+    Dense([u16; 40]),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CapsuleCabin {
+    /// 14 bit distance_q2 and the high 2 bits of the first angle offset
+    pub distance_angle_1: u16,
+    /// 14 bit distance_q2 and the high 2 bits of the second angle offset
+    pub distance_angle_2: u16,
+    /// The low 4 bits of both angle offsets
+    pub offset_angles_q3: u8,
 }
 
 impl ScanPacket {
-    fn from_buffer(bytes: &[u8; 132]) -> Self {
-        let timestamp = Instant::now();
+    /// Check the sync and checksum of a capsule, which share the same header in every express format, and split it into cabins
+    fn from_buffer(
+        bytes: &[u8],
+        format: ScanFormat,
+        timestamp: Instant,
+    ) -> Result<Self, LidarError> {
         let sync = (bytes[0] & 0xF0) | (bytes[1] >> 4);
-        assert_eq!(sync, 0xa5);
+        if sync != 0xa5 {
+            return Err(LidarError::InvalidSync(sync));
+        }
         let checksum = (bytes[0] & 0xF) | (bytes[1] << 4);
-        let mut check_checksum = 0;
-        for i in 2..bytes.len() {
-            check_checksum ^= bytes[i];
+        let check_checksum = bytes[2..].iter().fold(0, |acc, byte| acc ^ byte);
+        if checksum != check_checksum {
+            return Err(LidarError::InvalidChecksum {
+                expected: checksum,
+                actual: check_checksum,
+            });
         }
-        assert_eq!(checksum, check_checksum);
         let start_bit = bytes[3] & 0b1000_0000 != 0;
         let start_angle_q6 = u16::from_le_bytes([bytes[2], bytes[3] & 0b0111_1111]);
 
@@ -246,48 +916,266 @@ impl ScanPacket {
             println!("New Scan Started");
         }
 
-        let mut ultra_cabins = Vec::with_capacity(32);
-        for i in 0..32 {
-            let offset = i * 4;
-            let cabin = u32::from_le_bytes(bytes[(4 + offset)..(8 + offset)].try_into().unwrap());
-            ultra_cabins.push(cabin);
-        }
-        ScanPacket {
+        let cabins = match format {
+            ScanFormat::Capsuled => Cabins::Capsuled(std::array::from_fn(|i| {
+                let cabin = &bytes[(4 + i * 5)..(9 + i * 5)];
+                CapsuleCabin {
+                    distance_angle_1: u16::from_le_bytes([cabin[0], cabin[1]]),
+                    distance_angle_2: u16::from_le_bytes([cabin[2], cabin[3]]),
+                    offset_angles_q3: cabin[4],
+                }
+            })),
+            ScanFormat::UltraCapsuled => Cabins::Ultra(std::array::from_fn(|i| {
+                u32::from_le_bytes(bytes[(4 + i * 4)..(8 + i * 4)].try_into().unwrap())
+            })),
+            ScanFormat::DenseCapsuled => Cabins::Dense(std::array::from_fn(|i| {
+                u16::from_le_bytes([bytes[4 + i * 2], bytes[5 + i * 2]])
+            })),
+            ScanFormat::Standard => unreachable!("standard scans are sent as nodes, not capsules"),
+        };
+        Ok(ScanPacket {
             timestamp,
             start_bit,
             start_angle_q6,
-            ultra_cabins: ultra_cabins.try_into().unwrap(),
-        }
+            cabins,
+        })
     }
 
     fn get_start_angle_radians(&self) -> f32 {
         self.start_angle_q6 as f32 * std::f32::consts::PI / 180.0 / 64.0
     }
-}
 
-const DEFAULT_SCAN: LidarScan = LidarScan { points: Vec::new() };
+    /// Decode the points of this capsule. The points of a capsule lie between its start angle and the start angle of
+    /// the next one, so a capsule can only be decoded once the next one arrives.
+    fn points_until(&self, next: &ScanPacket) -> Vec<LidarPoint> {
+        match (&self.cabins, &next.cabins) {
+            (Cabins::Capsuled(cabins), _) => self.capsuled_points(next, cabins),
+            (Cabins::Ultra(cabins), Cabins::Ultra(next_cabins)) => {
+                self.ultra_points(next, cabins, next_cabins)
+            }
+            (Cabins::Dense(distances), _) => self.dense_points(next, distances),
+            _ => Vec::new(),
+        }
+    }
+
+    /// When the given fraction of the way from the start of this capsule to the start of the next one was measured
+    fn timestamp_at(&self, next: &ScanPacket, fraction: f64) -> Instant {
+        self.timestamp
+            + next
+                .timestamp
+                .saturating_duration_since(self.timestamp)
+                .mul_f64(fraction)
+    }
+
+    /// The angle between the start of this capsule and the next one in q8 degrees
+    fn angle_diff_q8(&self, next: &ScanPacket) -> i32 {
+        ((next.start_angle_q6 as i32 - self.start_angle_q6 as i32) << 2).rem_euclid(360 << 8)
+    }
+
+    fn capsuled_points(&self, next: &ScanPacket, cabins: &[CapsuleCabin; 16]) -> Vec<LidarPoint> {
+        let mut points = Vec::with_capacity(32);
+        let angle_inc_q16 = self.angle_diff_q8(next) << 3;
+        let mut angle_q16 = (self.start_angle_q6 as i32) << 10;
+        for cabin in cabins {
+            let measurements = [
+                (cabin.distance_angle_1, cabin.offset_angles_q3 & 0xF),
+                (cabin.distance_angle_2, cabin.offset_angles_q3 >> 4),
+            ];
+            for (j, (distance_angle, offset_low)) in measurements.into_iter().enumerate() {
+                // the compensation is subtracted from the evenly spaced angle of each measurement
+                let offset_q3 = (offset_low | ((distance_angle as u8 & 0b11) << 4)) as i32;
+                let angle_q6 = ((angle_q16 - (offset_q3 << 13)) >> 10).rem_euclid(360 << 6);
+                angle_q16 += angle_inc_q16;
+                points.push(LidarPoint {
+                    angle_q6: angle_q6 as u16,
+                    distance_mm: (distance_angle >> 2) as u32,
+                    quality: EXPRESS_QUALITY,
+                    index: j as u8,
+                    timestamp: self.timestamp_at(next, points.len() as f64 / 32.0),
+                });
+            }
+        }
+        points
+    }
+
+    fn ultra_points(
+        &self,
+        next: &ScanPacket,
+        cabins: &[u32; 32],
+        next_cabins: &[u32; 32],
+    ) -> Vec<LidarPoint> {
+        let mut points = Vec::with_capacity(96);
+        for i in 0..32 {
+            let mut dist_q2 = [0; 3];
+
+            let combined_x3 = cabins[i];
+
+            // unpack
+            let dist_major1 = combined_x3 & 0xFFF;
+            let mut dist_predict1 = ((combined_x3 as i32) << 10) >> 22;
+            let mut dist_predict2 = (combined_x3 as i32) >> 22;
+
+            let dist_major2 = if i == 31 {
+                next_cabins[0] & 0xFFF
+            } else {
+                cabins[i + 1] & 0xFFF
+            };
+
+            let mut scale_level1 = 0;
+            let mut scale_level2 = 0;
+
+            let dist_major1 = varbitscale_decode(dist_major1, &mut scale_level1);
+            let dist_major2 = varbitscale_decode(dist_major2, &mut scale_level2);
+
+            let mut dist_base1 = dist_major1;
+            let dist_base2 = dist_major2;
+
+            if dist_major1 == 0 && dist_major2 != 0 {
+                dist_base1 = dist_major2;
+                scale_level1 = scale_level2;
+            }
+
+            dist_q2[0] = dist_major1 << 2;
+            if dist_predict1 as u32 == 0xFFFFFE00 || dist_predict1 == 0x1FF {
+                dist_q2[1] = 0
+            } else {
+                dist_predict1 <<= scale_level1;
+                dist_q2[1] = ((dist_base1 as i32 + dist_predict1) << 2) as u32;
+            }
+
+            if dist_predict2 as u32 == 0xFFFFFE00 || dist_predict2 == 0x1FF {
+                dist_q2[2] = 0
+            } else {
+                dist_predict2 <<= scale_level2;
+                dist_q2[2] = ((dist_base2 as i32 + dist_predict2) << 2) as u32;
+            }
+
+            let start_angle_q6 = self.start_angle_q6 as i32;
+            let angle_diff_q6 = (next.start_angle_q6 as i32 - start_angle_q6).rem_euclid(360 * 64);
+
+            for (j, dist_q2) in dist_q2.into_iter().enumerate() {
+                points.push(LidarPoint {
+                    angle_q6: (start_angle_q6 as u16
+                        + (angle_diff_q6 as f64 * (i as f64 / 32.0 + j as f64 / 96.0)) as u16)
+                        % (360 * 64),
+                    distance_mm: dist_q2 >> 2,
+                    quality: EXPRESS_QUALITY,
+                    index: j as u8,
+                    timestamp: self.timestamp_at(next, (i * 3 + j) as f64 / 96.0),
+                });
+            }
+        }
+        points
+    }
+
+    fn dense_points(&self, next: &ScanPacket, distances: &[u16; 40]) -> Vec<LidarPoint> {
+        let angle_inc_q16 = (self.angle_diff_q8(next) << 8) / 40;
+        let start_angle_q16 = (self.start_angle_q6 as i32) << 10;
+        distances
+            .iter()
+            .enumerate()
+            .map(|(i, &distance)| LidarPoint {
+                angle_q6: (((start_angle_q16 + angle_inc_q16 * i as i32) >> 10) % (360 << 6))
+                    as u16,
+                distance_mm: distance as u32,
+                quality: EXPRESS_QUALITY,
+                index: 0,
+                timestamp: self.timestamp_at(next, i as f64 / 40.0),
+            })
+            .collect()
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct LidarScan {
     pub points: Vec<LidarPoint>,
+    /// When the first point of the scan was measured
+    pub start_time: Instant,
+    /// When the last point of the scan was measured
+    pub end_time: Instant,
+    /// Transform from the lidar's frame to the car's frame when the scan was taken
+    pub sensor_to_base: Isometry2<f64>,
 }
 
 impl LidarScan {
+    fn new(first_point: LidarPoint, sensor_to_base: Isometry2<f64>) -> Self {
+        LidarScan {
+            points: vec![first_point],
+            start_time: first_point.timestamp,
+            end_time: first_point.timestamp,
+            sensor_to_base,
+        }
+    }
+
+    /// A scan of `(degrees, distance_mm)` points all measured now, with the lidar at the origin of the car
+    #[cfg(test)]
+    pub fn from_polar(points: &[(f32, u32)]) -> Self {
+        let timestamp = Instant::now();
+        LidarScan {
+            points: points
+                .iter()
+                .map(|&(degrees, distance_mm)| LidarPoint {
+                    angle_q6: (degrees * 64.0) as u16,
+                    distance_mm,
+                    quality: EXPRESS_QUALITY,
+                    index: 0,
+                    timestamp,
+                })
+                .collect(),
+            start_time: timestamp,
+            end_time: timestamp,
+            sensor_to_base: Isometry2::identity(),
+        }
+    }
+
+    /// Cartesian points re-projected into the frame of the car at the end of the scan.
+    ///
+    /// `motion` is how the car moved from the start to the end of the scan, expressed in its frame at the start. The
+    /// motion is assumed to be at a constant velocity, so each point is moved back along it by the share of the scan
+    /// left after it was measured. This keeps a scan taken while driving from being smeared before it is matched.
+    pub fn deskew(&self, motion: &PositionDiff) -> Vec<Vector2<f64>> {
+        let duration = self.end_time.saturating_duration_since(self.start_time);
+        let translation = Vector2::new(motion.translation.x as f64, motion.translation.y as f64);
+        let rotation = motion.rotation as f64;
+        let end_to_start = Isometry2::new(translation, rotation).inverse();
+        self.points
+            .iter()
+            .map(|point| {
+                let fraction = if duration.is_zero() {
+                    1.0
+                } else {
+                    point
+                        .timestamp
+                        .saturating_duration_since(self.start_time)
+                        .as_secs_f64()
+                        / duration.as_secs_f64()
+                };
+                let pose = Isometry2::new(translation * fraction, rotation * fraction);
+                let point = point.to_cartesian();
+                (end_to_start * pose * self.sensor_to_base * Point2::new(point.x, point.y)).coords
+            })
+            .collect()
+    }
+
+    /// Cartesian points in the frame of the car
     pub fn to_cartesian_points(&self) -> Vec<Vector2<f64>> {
         self.points
             .iter()
-            .map(|point| point.to_cartesian())
+            .map(|point| {
+                let point = point.to_cartesian();
+                (self.sensor_to_base * Point2::new(point.x, point.y)).coords
+            })
             .collect()
     }
+    /// Distance to the closest point in 8 segments around the car, the first one centered straight ahead
     pub fn raycasts(&self) -> [u32; 8] {
+        let offset = std::f64::consts::PI / 8.0;
+        let ranges = sector_ranges(&self.to_cartesian_points(), -offset, -offset, 8);
         let mut lengths = [u32::MAX; 8];
-        let offset = 360.0 / 8 as f32 / 2.0;
-        for point in &self.points {
-            // offset by half a segment and wrap
-            let angle = (point.get_angle_degrees() + offset).rem_euclid(360.0);
-            let dist = point.distance_q0;
-            let segment = (angle / 360.0 * 8 as f32).floor() as usize;
-            lengths[segment] = lengths[segment].min(dist);
+        for (length, range) in lengths.iter_mut().zip(ranges) {
+            if let Some(range) = range {
+                *length = range.round() as u32;
+            }
         }
         lengths
     }
@@ -299,6 +1187,96 @@ pub enum LidarRequest {
     Reset,
     GetDeviceInfo,
     GetDeviceHealth,
+    Scan,
+    ExpressScan {
+        working_mode: u8,
+    },
+    GetLidarConf(LidarConf),
+    GetSampleRate,
+    /// Set the rotation speed of the motor, only supported on lidars with closed loop motor control
+    SetMotorSpeed {
+        rpm: u16,
+    },
+    /// Set the motor PWM directly (0 to 1023), for lidars driven through the accessory board
+    SetMotorPwm {
+        pwm: u16,
+    },
+}
+
+/// How the rotation speed of the lidar is controlled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MotorSpeed {
+    Rpm(u16),
+    Pwm(u16),
+}
+
+impl MotorSpeed {
+    fn request(&self) -> LidarRequest {
+        match *self {
+            MotorSpeed::Rpm(rpm) => LidarRequest::SetMotorSpeed { rpm },
+            MotorSpeed::Pwm(pwm) => LidarRequest::SetMotorPwm { pwm },
+        }
+    }
+}
+
+/// Time between two samples as reported by GET_SAMPLERATE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleRate {
+    /// Microseconds per sample in a standard scan
+    pub standard_us: u16,
+    /// Microseconds per sample in an express scan
+    pub express_us: u16,
+}
+
+/// Configuration entries which can be read with GET_LIDAR_CONF, added in firmware 1.24
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LidarConf {
+    ModeCount,
+    UsPerSample(u16),
+    MaxDistance(u16),
+    AnswerType(u16),
+    TypicalMode,
+    ModeName(u16),
+}
+
+impl LidarConf {
+    fn conf_type(&self) -> u32 {
+        match self {
+            LidarConf::ModeCount => 0x70,
+            LidarConf::UsPerSample(_) => 0x71,
+            LidarConf::MaxDistance(_) => 0x74,
+            LidarConf::AnswerType(_) => 0x75,
+            LidarConf::TypicalMode => 0x7C,
+            LidarConf::ModeName(_) => 0x7F,
+        }
+    }
+
+    /// The configuration type followed by the scan mode id for entries which belong to a scan mode
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = self.conf_type().to_le_bytes().to_vec();
+        match self {
+            LidarConf::UsPerSample(id)
+            | LidarConf::MaxDistance(id)
+            | LidarConf::AnswerType(id)
+            | LidarConf::ModeName(id) => payload.extend_from_slice(&id.to_le_bytes()),
+            LidarConf::ModeCount | LidarConf::TypicalMode => {}
+        }
+        payload
+    }
+}
+
+fn conf_u16(data: &[u8]) -> Result<u16, LidarError> {
+    match data {
+        [a, b, ..] => Ok(u16::from_le_bytes([*a, *b])),
+        _ => Err(LidarError::InvalidConf(0)),
+    }
+}
+
+fn conf_u32(data: &[u8]) -> Result<u32, LidarError> {
+    match data {
+        [a, b, c, d, ..] => Ok(u32::from_le_bytes([*a, *b, *c, *d])),
+        _ => Err(LidarError::InvalidConf(0)),
+    }
 }
 
 #[derive(Debug)]
@@ -310,53 +1288,296 @@ pub enum LidarResponse {
         hardware: u8,
         serial: [u8; 16],
     },
+    DeviceHealth {
+        status: HealthStatus,
+        error_code: u16,
+    },
+    LidarConf {
+        conf_type: u32,
+        data: Vec<u8>,
+    },
+    SampleRate(SampleRate),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthStatus {
+    Good,
+    Warning,
+    Error,
+}
+
+/// How many responses follow a response descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendMode {
+    Single,
+    Multiple,
+}
+
+/// The 7 byte header the lidar sends before the payload of any response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResponseDescriptor {
+    /// Length of a single response payload in bytes
+    pub length: u32,
+    pub send_mode: SendMode,
+    pub data_type: u8,
+}
+
+const DATA_TYPE_DEVICE_INFO: u8 = 0x04;
+const DATA_TYPE_DEVICE_HEALTH: u8 = 0x06;
+const DATA_TYPE_LIDAR_CONF: u8 = 0x20;
+const DATA_TYPE_SAMPLE_RATE: u8 = 0x15;
+
 impl LidarRequest {
+    /// Every request starts with 0xa5 and the command byte. Requests with a payload follow it with the payload size,
+    /// the payload itself and a checksum which is the XOR of every byte before it.
+    fn to_bytes(&self) -> Vec<u8> {
+        let (command, payload) = match self {
+            LidarRequest::Stop => (0x25, Vec::new()),
+            LidarRequest::Reset => (0x40, Vec::new()),
+            LidarRequest::GetDeviceInfo => (0x50, Vec::new()),
+            LidarRequest::GetDeviceHealth => (0x52, Vec::new()),
+            LidarRequest::Scan => (0x20, Vec::new()),
+            // working mode, 16 bit work flags, 16 bit param
+            LidarRequest::ExpressScan { working_mode } => (0x82, vec![*working_mode, 0, 0, 0, 0]),
+            LidarRequest::GetLidarConf(conf) => (0x84, conf.payload()),
+            LidarRequest::GetSampleRate => (0x59, Vec::new()),
+            LidarRequest::SetMotorSpeed { rpm } => (0xA8, rpm.to_le_bytes().to_vec()),
+            LidarRequest::SetMotorPwm { pwm } => (0xF0, pwm.to_le_bytes().to_vec()),
+        };
+        let mut bytes = vec![0xa5, command];
+        if !payload.is_empty() {
+            bytes.push(payload.len() as u8);
+            bytes.extend_from_slice(&payload);
+            bytes.push(bytes.iter().fold(0, |acc, byte| acc ^ byte));
+        }
+        bytes
+    }
+
     pub async fn write(&self, port: &mut SerialStream) -> Result<(), tokio_serial::Error> {
+        port.write_all(&self.to_bytes()).await?;
+        Ok(())
+    }
+}
+
+impl ResponseDescriptor {
+    fn from_bytes(bytes: &[u8; 7]) -> Result<Self, LidarError> {
+        if bytes[0] != 0xa5 || bytes[1] != 0x5a {
+            return Err(LidarError::InvalidDescriptor(*bytes));
+        }
+        // 30bit length, 2bit send_mode, 1byte data_type
+        let length_and_mode = u32::from_le_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]);
+        let send_mode = match length_and_mode >> 30 {
+            0 => SendMode::Single,
+            1 => SendMode::Multiple,
+            _ => return Err(LidarError::InvalidDescriptor(*bytes)),
+        };
+        Ok(ResponseDescriptor {
+            length: length_and_mode & 0x3FFF_FFFF,
+            send_mode,
+            data_type: bytes[6],
+        })
+    }
+
+    pub async fn read(port: &mut SerialStream) -> Result<Self, LidarError> {
+        let mut bytes = [0; 7];
+        port.read_exact(&mut bytes).await?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl LidarResponse {
+    /// Read the response to a [`LidarRequest::GetDeviceInfo`] or [`LidarRequest::GetDeviceHealth`], checking that the
+    /// descriptor's length and send mode match what is expected for its data type.
+    pub async fn read(port: &mut SerialStream) -> Result<Self, LidarError> {
+        let descriptor = ResponseDescriptor::read(port).await?;
+        let valid_length = match descriptor.data_type {
+            DATA_TYPE_DEVICE_INFO => descriptor.length == 20,
+            DATA_TYPE_DEVICE_HEALTH => descriptor.length == 3,
+            DATA_TYPE_SAMPLE_RATE => descriptor.length == 4,
+            // the echoed configuration type followed by data of a length depending on the type
+            DATA_TYPE_LIDAR_CONF => (4..=256).contains(&descriptor.length),
+            _ => false,
+        };
+        if !valid_length || descriptor.send_mode != SendMode::Single {
+            return Err(LidarError::UnexpectedResponse(descriptor));
+        }
+        let mut payload = vec![0; descriptor.length as usize];
+        port.read_exact(&mut payload).await?;
+        Ok(Self::from_payload(descriptor.data_type, &payload))
+    }
+
+    /// Decode a payload whose length has already been checked against its data type
+    fn from_payload(data_type: u8, payload: &[u8]) -> Self {
+        if data_type == DATA_TYPE_LIDAR_CONF {
+            LidarResponse::LidarConf {
+                conf_type: u32::from_le_bytes(payload[..4].try_into().unwrap()),
+                data: payload[4..].to_vec(),
+            }
+        } else if data_type == DATA_TYPE_SAMPLE_RATE {
+            LidarResponse::SampleRate(SampleRate {
+                standard_us: u16::from_le_bytes([payload[0], payload[1]]),
+                express_us: u16::from_le_bytes([payload[2], payload[3]]),
+            })
+        } else if data_type == DATA_TYPE_DEVICE_HEALTH {
+            let status = match payload[0] {
+                0 => HealthStatus::Good,
+                1 => HealthStatus::Warning,
+                _ => HealthStatus::Error,
+            };
+            LidarResponse::DeviceHealth {
+                status,
+                error_code: u16::from_le_bytes([payload[1], payload[2]]),
+            }
+        } else {
+            let mut serial = [0; 16];
+            serial.copy_from_slice(&payload[4..20]);
+            LidarResponse::DeviceInfo {
+                model: payload[0],
+                firmware_minor: payload[1],
+                firmware_major: payload[2],
+                hardware: payload[3],
+                serial,
+            }
+        }
+    }
+}
+
+/// Drops bytes from the front of the buffer until it looks like the start of a packet again, returning how many bytes
+/// were skipped. At least one byte is always skipped so a packet which only failed a later check isn't decoded again.
+fn resync(buffer: &mut Vec<u8>, is_packet_start: fn(&[u8]) -> bool) -> usize {
+    let skip = (1..buffer.len())
+        .find(|&i| is_packet_start(&buffer[i..]))
+        .unwrap_or(buffer.len());
+    buffer.drain(..skip);
+    skip
+}
+
+/// Whether the bytes could be the start of a capsule, judging by the 0xA/0x5 sync nibbles that have arrived so far
+fn is_capsule_start(bytes: &[u8]) -> bool {
+    bytes[0] >> 4 == 0xA && bytes.get(1).is_none_or(|byte| byte >> 4 == 0x5)
+}
+
+/// Whether the bytes could be the start of a standard scan node, judging by the start flag pair and the check bit
+fn is_node_start(bytes: &[u8]) -> bool {
+    (bytes[0] & 1) != ((bytes[0] >> 1) & 1) && bytes.get(1).is_none_or(|byte| byte & 1 == 1)
+}
+
+#[derive(Debug)]
+pub enum LidarError {
+    Io(std::io::Error),
+    Serial(tokio_serial::Error),
+    /// The sync nibbles at the start of a packet weren't 0xA and 0x5
+    InvalidSync(u8),
+    /// The checksum at the start of a packet doesn't match the XOR of its payload
+    InvalidChecksum {
+        expected: u8,
+        actual: u8,
+    },
+    /// The start flags or the check bit of a standard scan node are wrong
+    InvalidNode([u8; SCAN_NODE_LEN]),
+    /// A response descriptor didn't start with 0xa5 0x5a or had a reserved send mode
+    InvalidDescriptor([u8; 7]),
+    /// A response descriptor had a data type, length or send mode that doesn't fit the request
+    UnexpectedResponse(ResponseDescriptor),
+    /// The lidar didn't answer a request in time
+    Timeout,
+    /// A GET_LIDAR_CONF answer was for a different configuration type or too short for its value
+    InvalidConf(u32),
+    /// None of the scan modes reported by the lidar can be decoded, or the configured one doesn't exist
+    NoScanMode,
+    /// The lidar reported an error through its health status, it needs to be reset or power cycled
+    Unhealthy {
+        error_code: u16,
+    },
+}
+
+impl std::fmt::Display for LidarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            LidarRequest::Stop => {
-                port.write(&[0xa5, 0x25]).await?;
+            LidarError::Io(err) => write!(f, "io error: {}", err),
+            LidarError::Serial(err) => write!(f, "serial error: {}", err),
+            LidarError::InvalidSync(sync) => write!(f, "invalid sync byte {:#04x}", sync),
+            LidarError::InvalidChecksum { expected, actual } => write!(
+                f,
+                "invalid checksum, expected {:#04x} but got {:#04x}",
+                expected, actual
+            ),
+            LidarError::InvalidNode(bytes) => write!(f, "invalid scan node {:02x?}", bytes),
+            LidarError::InvalidDescriptor(bytes) => {
+                write!(f, "invalid response descriptor {:02x?}", bytes)
             }
-            LidarRequest::Reset => {
-                port.write(&[0xa5, 0x40]).await?;
+            LidarError::UnexpectedResponse(descriptor) => {
+                write!(f, "unexpected response {:?}", descriptor)
             }
-            LidarRequest::GetDeviceInfo => {
-                port.write(&[0xa5, 0x50]).await?;
+            LidarError::Timeout => write!(f, "timed out waiting for a response"),
+            LidarError::InvalidConf(conf_type) => {
+                write!(f, "invalid answer for configuration type {:#x}", conf_type)
             }
-            LidarRequest::GetDeviceHealth => {
-                port.write(&[0xa5, 0x52]).await?;
+            LidarError::NoScanMode => write!(f, "no usable express scan mode"),
+            LidarError::Unhealthy { error_code } => {
+                write!(
+                    f,
+                    "lidar health check failed with error code {:#06x}",
+                    error_code
+                )
             }
         }
-        Ok(())
     }
 }
 
-impl LidarResponse {
-    /// doesn't work yet. this might not be necessary
-    pub async fn read(port: &mut SerialStream) -> Result<Self, tokio_serial::Error> {
-        let mut response_descriptor = [0; 7];
-        port.read_exact(&mut response_descriptor).await?;
-        assert!(response_descriptor[0] == 0xa5);
-        assert!(response_descriptor[1] == 0x5a);
-        // 30bit length
-        // 2bit send_mode
-        // 1byte data_type
-
-        let data_type = response_descriptor[6];
-        let send_mode = response_descriptor[5] & 0b11;
-        let length = ((response_descriptor[5] as u32) << 8) | response_descriptor[4] as u32;
-        let mut buffer = [0; 20];
-        port.read_exact(&mut buffer).await?;
-        let mut serial = [0; 16];
-        serial.copy_from_slice(&buffer[4..20]);
-        Ok(LidarResponse::DeviceInfo {
-            model: buffer[0],
-            firmware_minor: buffer[1],
-            firmware_major: buffer[2],
-            hardware: buffer[3],
-            serial,
-        })
+impl std::error::Error for LidarError {}
+
+#[derive(Debug)]
+pub enum LidarInitError {
+    /// The lidar kept reporting an error through its health status, even after being reset
+    Unhealthy { error_code: u16 },
+    /// The lidar didn't start scanning in any of the attempts
+    Failed {
+        attempts: u32,
+        last_error: LidarError,
+    },
+}
+
+impl std::fmt::Display for LidarInitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LidarInitError::Unhealthy { error_code } => {
+                write!(f, "lidar is unhealthy with error code {:#06x}", error_code)
+            }
+            LidarInitError::Failed {
+                attempts,
+                last_error,
+            } => write!(
+                f,
+                "lidar failed to start after {} attempts, last error: {}",
+                attempts, last_error
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LidarInitError {}
+
+/// What the lidar task is up to, reported to clients
+#[derive(Debug, Clone)]
+pub enum LidarStatus {
+    Initializing,
+    /// Scanning, at the rotation frequency measured from the scans once two of them have completed
+    Running {
+        scan_frequency_hz: Option<f32>,
+    },
+    Failed(String),
+}
+
+impl From<std::io::Error> for LidarError {
+    fn from(err: std::io::Error) -> Self {
+        LidarError::Io(err)
+    }
+}
+
+impl From<tokio_serial::Error> for LidarError {
+    fn from(err: tokio_serial::Error) -> Self {
+        LidarError::Serial(err)
     }
 }
 
@@ -393,3 +1614,378 @@ fn test_varbitscale_decode() {
     assert_eq!(varbitscale_decode(0, &mut scale_level), 0);
     assert_eq!(scale_level, 0);
 }
+
+#[cfg(test)]
+fn capsule_with_checksum(start_angle_q6: u16) -> [u8; ULTRA_CAPSULE_LEN] {
+    let mut bytes = [0; ULTRA_CAPSULE_LEN];
+    for (i, byte) in bytes[4..].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    set_capsule_header(&mut bytes, start_angle_q6);
+    bytes
+}
+
+#[cfg(test)]
+fn set_capsule_header(bytes: &mut [u8], start_angle_q6: u16) {
+    bytes[2..4].copy_from_slice(&start_angle_q6.to_le_bytes());
+    let checksum = bytes[2..].iter().fold(0, |acc, byte| acc ^ byte);
+    bytes[0] = 0xA0 | (checksum & 0xF);
+    bytes[1] = 0x50 | (checksum >> 4);
+}
+
+#[test]
+fn test_scan_packet_rejects_corruption() {
+    let mut bytes = capsule_with_checksum(90 * 64);
+    let packet =
+        ScanPacket::from_buffer(&bytes, ScanFormat::UltraCapsuled, Instant::now()).unwrap();
+    assert_eq!(packet.start_angle_q6, 90 * 64);
+    assert!(
+        matches!(packet.cabins, Cabins::Ultra(cabins) if cabins[0] == u32::from_le_bytes([0, 1, 2, 3]))
+    );
+
+    bytes[40] ^= 0x10;
+    assert!(matches!(
+        ScanPacket::from_buffer(&bytes, ScanFormat::UltraCapsuled, Instant::now()),
+        Err(LidarError::InvalidChecksum { .. })
+    ));
+    bytes[1] = 0x00;
+    assert!(matches!(
+        ScanPacket::from_buffer(&bytes, ScanFormat::UltraCapsuled, Instant::now()),
+        Err(LidarError::InvalidSync(0xa0))
+    ));
+}
+
+#[test]
+fn test_resync() {
+    let capsule = capsule_with_checksum(0);
+    let mut buffer = vec![0x12, 0xa3, 0x34];
+    buffer.extend_from_slice(&capsule);
+    assert_eq!(resync(&mut buffer, is_capsule_start), 3);
+    assert_eq!(&buffer[..], &capsule[..]);
+    // a lone 0xA nibble at the end might be the start of a sync pair which hasn't arrived yet
+    let mut buffer = vec![0xa5, 0x00, 0xa1];
+    assert_eq!(resync(&mut buffer, is_capsule_start), 2);
+    assert_eq!(buffer, vec![0xa1]);
+    let mut buffer = vec![0xa5, 0x00];
+    assert_eq!(resync(&mut buffer, is_capsule_start), 2);
+    assert!(buffer.is_empty());
+}
+
+#[test]
+fn test_scan_node() {
+    // quality 15, start flag, 90 degrees, 1000mm
+    let angle = ((90 * 64) << 1 | 1u16).to_le_bytes();
+    let distance = (1000u16 << 2).to_le_bytes();
+    let node = [15 << 2 | 0b01, angle[0], angle[1], distance[0], distance[1]];
+    let point = LidarPoint::from_scan_node(&node, Instant::now()).unwrap();
+    assert_eq!(point.angle_q6, 90 * 64);
+    assert_eq!(point.distance_mm, 1000);
+    assert_eq!(point.quality, 15);
+
+    let mut buffer = vec![0b11, 0x01, 0x00];
+    buffer.extend_from_slice(&node);
+    assert_eq!(resync(&mut buffer, is_node_start), 3);
+    assert!(LidarPoint::from_scan_node(&[0b10, 0x00, 0, 0, 0], Instant::now()).is_err());
+}
+
+#[test]
+fn test_request_bytes() {
+    assert_eq!(
+        LidarRequest::ExpressScan { working_mode: 3 }.to_bytes(),
+        vec![0xa5, 0x82, 0x05, 0x03, 0x00, 0x00, 0x00, 0x00, 0x21]
+    );
+    assert_eq!(LidarRequest::Scan.to_bytes(), vec![0xa5, 0x20]);
+    assert_eq!(
+        LidarRequest::SetMotorSpeed { rpm: 600 }.to_bytes(),
+        vec![
+            0xa5,
+            0xa8,
+            0x02,
+            0x58,
+            0x02,
+            0xa5 ^ 0xa8 ^ 0x02 ^ 0x58 ^ 0x02
+        ]
+    );
+}
+
+#[test]
+fn test_response_descriptor() {
+    let descriptor =
+        ResponseDescriptor::from_bytes(&[0xa5, 0x5a, 0x84, 0x00, 0x00, 0x40, 0x84]).unwrap();
+    assert_eq!(descriptor.length, 0x84);
+    assert_eq!(descriptor.send_mode, SendMode::Multiple);
+    assert_eq!(descriptor.data_type, 0x84);
+    let descriptor =
+        ResponseDescriptor::from_bytes(&[0xa5, 0x5a, 0x03, 0x00, 0x00, 0x00, 0x06]).unwrap();
+    assert_eq!(descriptor.length, 3);
+    assert_eq!(descriptor.send_mode, SendMode::Single);
+    assert!(ResponseDescriptor::from_bytes(&[0xa5, 0x5a, 0x03, 0x00, 0x00, 0x80, 0x06]).is_err());
+    assert!(ResponseDescriptor::from_bytes(&[0xa5, 0x00, 0x03, 0x00, 0x00, 0x00, 0x06]).is_err());
+
+    assert!(matches!(
+        LidarResponse::from_payload(DATA_TYPE_DEVICE_HEALTH, &[2, 0x34, 0x12]),
+        LidarResponse::DeviceHealth {
+            status: HealthStatus::Error,
+            error_code: 0x1234
+        }
+    ));
+    assert!(matches!(
+        LidarResponse::from_payload(DATA_TYPE_SAMPLE_RATE, &[0xf4, 0x01, 0x7d, 0x00]),
+        LidarResponse::SampleRate(SampleRate {
+            standard_us: 500,
+            express_us: 125
+        })
+    ));
+}
+
+#[test]
+fn test_dense_and_legacy_capsules() {
+    let mut dense = [0; CAPSULE_LEN];
+    for i in 0..40 {
+        dense[4 + i * 2..6 + i * 2].copy_from_slice(&(1000 + i as u16).to_le_bytes());
+    }
+    set_capsule_header(&mut dense, 10 * 64);
+    let mut next = [0; CAPSULE_LEN];
+    set_capsule_header(&mut next, 20 * 64);
+    let start = Instant::now();
+    let first = ScanPacket::from_buffer(&dense, ScanFormat::DenseCapsuled, start).unwrap();
+    let second = ScanPacket::from_buffer(
+        &next,
+        ScanFormat::DenseCapsuled,
+        start + Duration::from_millis(4),
+    )
+    .unwrap();
+    let points = first.points_until(&second);
+    assert_eq!(points.len(), 40);
+    assert_eq!(points[10].timestamp, start + Duration::from_millis(1));
+    assert_eq!(points[0].angle_q6, 10 * 64);
+    assert_eq!(points[20].angle_q6, 15 * 64);
+    assert_eq!(points[39].distance_mm, 1039);
+
+    // one cabin with 2000mm and 3000mm, the second compensated by 1 degree (8 in q3, high bits 0b00)
+    let mut legacy = [0; CAPSULE_LEN];
+    legacy[4..6].copy_from_slice(&(2000u16 << 2).to_le_bytes());
+    legacy[6..8].copy_from_slice(&(3000u16 << 2).to_le_bytes());
+    legacy[8] = 0x80;
+    set_capsule_header(&mut legacy, 350 * 64);
+    let mut next = [0; CAPSULE_LEN];
+    set_capsule_header(&mut next, 2 * 64);
+    let first = ScanPacket::from_buffer(&legacy, ScanFormat::Capsuled, start).unwrap();
+    let second = ScanPacket::from_buffer(&next, ScanFormat::Capsuled, start).unwrap();
+    let points = first.points_until(&second);
+    assert_eq!(points.len(), 32);
+    assert_eq!(points[0].angle_q6, 350 * 64);
+    assert_eq!(points[0].distance_mm, 2000);
+    // 12 degrees over 32 points wrapping past 0, minus the compensation
+    assert_eq!(points[1].angle_q6, 350 * 64 + 24 - 64);
+    assert_eq!(points[1].distance_mm, 3000);
+    assert!(points[31].angle_q6 < 2 * 64);
+}
+
+#[test]
+fn test_pick_express_mode() {
+    let mode = |id, name: &str, us_per_sample, format| ScanModeInfo {
+        id,
+        name: name.to_string(),
+        us_per_sample,
+        max_distance: 12.0,
+        format,
+    };
+    let modes = [
+        mode(0, "Standard", 508.0, Some(ScanFormat::Standard)),
+        mode(1, "Express", 254.0, Some(ScanFormat::Capsuled)),
+        mode(2, "Boost", 127.0, Some(ScanFormat::UltraCapsuled)),
+        mode(3, "Sensitivity", 127.0, Some(ScanFormat::UltraCapsuled)),
+        mode(4, "HQ", 100.0, None),
+    ];
+    assert_eq!(pick_express_mode(&modes, 3, None).unwrap().id, 3);
+    assert_eq!(pick_express_mode(&modes, 0, None).unwrap().id, 2);
+    assert_eq!(pick_express_mode(&modes, 3, Some("Express")).unwrap().id, 1);
+    assert!(pick_express_mode(&modes, 3, Some("HQ")).is_none());
+}
+
+#[test]
+fn test_deskew() {
+    // a wall 1000mm ahead measured from 45 to -45 degrees while driving 100mm towards it
+    let start = Instant::now();
+    let scan_duration = Duration::from_millis(100);
+    let points: Vec<LidarPoint> = (0..=90)
+        .map(|i| {
+            let angle = (45.0 - i as f64).to_radians();
+            let fraction = i as f64 / 90.0;
+            LidarPoint {
+                angle_q6: (angle.to_degrees().rem_euclid(360.0) * 64.0) as u16,
+                distance_mm: ((1000.0 - 100.0 * fraction) / angle.cos()).round() as u32,
+                quality: EXPRESS_QUALITY,
+                index: 0,
+                timestamp: start + scan_duration.mul_f64(fraction),
+            }
+        })
+        .collect();
+    let scan = LidarScan {
+        points,
+        start_time: start,
+        end_time: start + scan_duration,
+        sensor_to_base: Isometry2::identity(),
+    };
+    let motion = PositionDiff {
+        translation: nalgebra::Vector2::new(100.0, 0.0),
+        rotation: 0.0,
+        covariance: nalgebra::Matrix3::zeros(),
+    };
+    // without de-skewing the wall looks slanted, with it every point sits 900mm ahead
+    for point in scan.deskew(&motion) {
+        assert!((point.x - 900.0).abs() < 2.0, "{:?}", point);
+    }
+}
+
+#[tokio::test]
+async fn test_replay_dense_capsules() {
+    use crate::lidar_recording::ReplayTiming;
+    use futures::StreamExt;
+
+    let mut log = b"LIDARLOG".to_vec();
+    log.extend_from_slice(&[1, 0x85]);
+    log.extend_from_slice(&125.0f32.to_le_bytes());
+    // a little more than one revolution, every capsule covering 30 degrees
+    for (i, start_angle) in (0..14).map(|i| (i, i % 12 * 30)) {
+        let mut capsule = [0; CAPSULE_LEN];
+        for cabin in capsule[4..].chunks_mut(2) {
+            cabin.copy_from_slice(&1000u16.to_le_bytes());
+        }
+        set_capsule_header(&mut capsule, start_angle * 64);
+        log.extend_from_slice(&(i as u64 * 5000).to_le_bytes());
+        log.extend_from_slice(&(CAPSULE_LEN as u16).to_le_bytes());
+        log.extend_from_slice(&capsule);
+    }
+
+    let replay = LidarReplay::from_bytes(&log, ReplayTiming::AsFastAsPossible).unwrap();
+    let mut engine = LidarEngine::from_replay(replay, LidarConfig::default());
+    let scan = engine.next().await.unwrap().unwrap();
+    assert_eq!(scan.points.len(), 12 * 40);
+    assert!(scan.points.iter().all(|point| point.distance_mm == 1000));
+    assert_eq!(
+        scan.end_time - scan.start_time,
+        Duration::from_micros(125 * (12 * 40 - 1))
+    );
+    assert!(engine.next().await.is_none());
+    assert_eq!(engine.dropped_packets, 0);
+}
+
+#[tokio::test]
+async fn test_recording_keeps_corrupt_packets() {
+    use crate::lidar_recording::ReplayTiming;
+    use futures::StreamExt;
+
+    let mut log = b"LIDARLOG".to_vec();
+    log.extend_from_slice(&[1, 0x85]);
+    log.extend_from_slice(&125.0f32.to_le_bytes());
+    for (i, start_angle) in (0..14).map(|i| (i, i % 12 * 30)) {
+        let mut capsule = [0; CAPSULE_LEN];
+        for cabin in capsule[4..].chunks_mut(2) {
+            cabin.copy_from_slice(&1000u16.to_le_bytes());
+        }
+        set_capsule_header(&mut capsule, start_angle * 64);
+        // line noise in front of one capsule and a bad checksum on another
+        if i == 3 {
+            capsule[0] ^= 0x0F;
+        }
+        if i == 7 {
+            capsule[..3].copy_from_slice(&[0x00, 0xFF, 0x12]);
+        }
+        log.extend_from_slice(&(i as u64 * 5000).to_le_bytes());
+        log.extend_from_slice(&(CAPSULE_LEN as u16).to_le_bytes());
+        log.extend_from_slice(&capsule);
+    }
+
+    // decode a log while recording it again, then decode the new recording
+    let path = std::env::temp_dir().join(format!("lidar_rerecord_{}.bin", std::process::id()));
+    async fn decode(replay: LidarReplay, record_to: Option<&Path>) -> (Vec<usize>, u32) {
+        let mut engine = LidarEngine::from_replay(replay, LidarConfig::default());
+        if let Some(path) = record_to {
+            engine.record_to(path).unwrap();
+        }
+        let mut points = Vec::new();
+        while let Some(scan) = engine.next().await {
+            if let Ok(scan) = scan {
+                points.push(scan.points.len());
+            }
+        }
+        (points, engine.dropped_packets)
+    }
+    let replay = LidarReplay::from_bytes(&log, ReplayTiming::AsFastAsPossible).unwrap();
+    let (points, dropped_packets) = decode(replay, Some(&path)).await;
+    assert!(dropped_packets > 0);
+
+    let replay = LidarReplay::open(&path, ReplayTiming::AsFastAsPossible).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(decode(replay, None).await, (points, dropped_packets));
+}
+
+#[tokio::test]
+async fn test_scan_frequency() {
+    use crate::lidar_recording::ReplayTiming;
+    use futures::StreamExt;
+
+    let mut log = b"LIDARLOG".to_vec();
+    log.extend_from_slice(&[1, 0x85]);
+    log.extend_from_slice(&125.0f32.to_le_bytes());
+    // three revolutions of 480 samples at 125us each, 60ms per revolution
+    for (i, start_angle) in (0..38).map(|i| (i, i % 12 * 30)) {
+        let mut capsule = [0; CAPSULE_LEN];
+        for cabin in capsule[4..].chunks_mut(2) {
+            cabin.copy_from_slice(&1000u16.to_le_bytes());
+        }
+        set_capsule_header(&mut capsule, start_angle * 64);
+        log.extend_from_slice(&(i as u64 * 5000).to_le_bytes());
+        log.extend_from_slice(&(CAPSULE_LEN as u16).to_le_bytes());
+        log.extend_from_slice(&capsule);
+    }
+
+    let replay = LidarReplay::from_bytes(&log, ReplayTiming::AsFastAsPossible).unwrap();
+    let mut engine = LidarEngine::from_replay(replay, LidarConfig::default());
+    engine.next().await.unwrap().unwrap();
+    assert_eq!(engine.scan_frequency_hz(), None);
+    while engine.next().await.is_some() {}
+    let frequency = engine.scan_frequency_hz().unwrap();
+    assert!((frequency - 1000.0 / 60.0).abs() < 0.1, "{}", frequency);
+}
+
+#[test]
+fn test_lidar_mount() {
+    let mount = LidarMount {
+        x: 100.0,
+        y: 0.0,
+        yaw: std::f64::consts::PI,
+        masks: vec![AngleMask {
+            start: 350.0,
+            end: 10.0,
+        }],
+    };
+    let masked = LidarScan::from_polar(&[(355.0, 50), (5.0, 50), (180.0, 500)]);
+    assert!(mount.is_masked(&masked.points[0]));
+    assert!(mount.is_masked(&masked.points[1]));
+    assert!(!mount.is_masked(&masked.points[2]));
+
+    // the lidar is mounted backwards 100mm in front of the origin, so its 180 degrees is straight ahead
+    let mut scan = LidarScan::from_polar(&[(180.0, 500), (0.0, 300)]);
+    scan.sensor_to_base = mount.sensor_to_base();
+    let points = scan.to_cartesian_points();
+    assert!((points[0] - Vector2::new(600.0, 0.0)).norm() < 1e-6);
+    assert!((points[1] - Vector2::new(-200.0, 0.0)).norm() < 1e-6);
+    let raycasts = scan.raycasts();
+    assert_eq!(raycasts[0], 600);
+    assert_eq!(raycasts[4], 200);
+
+    let parsed =
+        LidarMount::parse("x 100 # in front of the origin\n\nyaw 180\nmask 350 10\nmask 170 190")
+            .unwrap();
+    assert_eq!((parsed.x, parsed.y), (100.0, 0.0));
+    assert!((parsed.yaw - std::f64::consts::PI).abs() < 1e-12);
+    assert_eq!(parsed.masks.len(), 2);
+    assert!(parsed.is_masked(&masked.points[0]));
+    assert!(matches!(
+        LidarMount::parse("yaw 180\nmask 350"),
+        Err(LidarMountError::Parse { line: 2, .. })
+    ));
+}
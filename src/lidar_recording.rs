@@ -0,0 +1,207 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::Sleep;
+
+use crate::lidar::ScanFormat;
+
+const MAGIC: &[u8; 8] = b"LIDARLOG";
+/// Version 1 logs held one decoded packet per record, which replays the same way
+const VERSION: u8 = 2;
+/// Magic, version, answer type and microseconds per sample
+const HEADER_LEN: usize = 8 + 1 + 1 + 4;
+/// Microseconds since the recording started and read length
+const RECORD_HEADER_LEN: usize = 8 + 2;
+
+/// Writes the raw bytes read from the lidar to a log file which can be replayed with `LidarReplay`
+///
+/// The file starts with a header: b"LIDARLOG", a version byte, the answer type of the scan format and the time between
+/// two samples in microseconds as a little endian f32 (0 if unknown). Every read follows as a record: the microseconds
+/// since the recording started when it arrived (u64 LE), the number of bytes read (u16 LE) and the bytes.
+pub struct LidarRecorder {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl LidarRecorder {
+    pub fn create(
+        path: impl AsRef<Path>,
+        format: ScanFormat,
+        sample_duration: Option<Duration>,
+    ) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let us_per_sample =
+            sample_duration.map_or(0.0, |duration| (duration.as_secs_f64() * 1e6) as f32);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION, format.answer_type()])?;
+        writer.write_all(&us_per_sample.to_le_bytes())?;
+        Ok(LidarRecorder {
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Append bytes which arrived at `timestamp`
+    pub fn record(&mut self, timestamp: Instant, bytes: &[u8]) -> io::Result<()> {
+        let offset = timestamp.saturating_duration_since(self.start).as_micros() as u64;
+        self.writer.write_all(&offset.to_le_bytes())?;
+        self.writer.write_all(&(bytes.len() as u16).to_le_bytes())?;
+        self.writer.write_all(bytes)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayTiming {
+    /// Hand out every record at the same time after the start of the replay as it was recorded
+    Original,
+    /// Hand out records as soon as they are read
+    AsFastAsPossible,
+}
+
+/// Reads a log written by `LidarRecorder` and hands out the bytes as if they came from the serial port, one record per
+/// read
+pub struct LidarReplay {
+    pub format: ScanFormat,
+    /// Time between two samples in microseconds, None if the lidar didn't report it while recording
+    pub us_per_sample: Option<f32>,
+    timing: ReplayTiming,
+    records: VecDeque<(Duration, Vec<u8>)>,
+    start: Instant,
+    /// When the last record handed out was originally recorded, relative to the start of the replay
+    last_offset: Duration,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl LidarReplay {
+    pub fn open(path: impl AsRef<Path>, timing: ReplayTiming) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Self::from_bytes(&bytes, timing)
+    }
+
+    pub fn from_bytes(bytes: &[u8], timing: ReplayTiming) -> io::Result<Self> {
+        if bytes.len() < HEADER_LEN || &bytes[..8] != MAGIC {
+            return Err(invalid_data("not a lidar log"));
+        }
+        if bytes[8] != VERSION && bytes[8] != 1 {
+            return Err(invalid_data("unsupported lidar log version"));
+        }
+        let format = ScanFormat::from_answer_type(bytes[9])
+            .ok_or_else(|| invalid_data("unknown scan format in lidar log"))?;
+        let us_per_sample = f32::from_le_bytes(bytes[10..14].try_into().unwrap());
+
+        let mut records = VecDeque::new();
+        let mut rest = &bytes[HEADER_LEN..];
+        while !rest.is_empty() {
+            if rest.len() < RECORD_HEADER_LEN {
+                return Err(invalid_data("truncated lidar log record"));
+            }
+            let offset = u64::from_le_bytes(rest[..8].try_into().unwrap());
+            let len = u16::from_le_bytes(rest[8..10].try_into().unwrap()) as usize;
+            rest = &rest[RECORD_HEADER_LEN..];
+            if rest.len() < len {
+                return Err(invalid_data("truncated lidar log record"));
+            }
+            records.push_back((Duration::from_micros(offset), rest[..len].to_vec()));
+            rest = &rest[len..];
+        }
+
+        Ok(LidarReplay {
+            format,
+            us_per_sample: (us_per_sample > 0.0).then_some(us_per_sample),
+            timing,
+            records,
+            start: Instant::now(),
+            last_offset: Duration::ZERO,
+            delay: None,
+        })
+    }
+
+    /// When the last record handed out would have arrived, had it come from the lidar
+    pub fn read_time(&self) -> Instant {
+        self.start + self.last_offset
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl AsyncRead for LidarReplay {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let replay = self.get_mut();
+        // an empty read signals the end of the recording
+        let Some((offset, _)) = replay.records.front() else {
+            return Poll::Ready(Ok(()));
+        };
+        let offset = *offset;
+        if replay.timing == ReplayTiming::Original {
+            let deadline = tokio::time::Instant::from_std(replay.start + offset);
+            let delay = replay
+                .delay
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            replay.delay = None;
+        }
+
+        let (_, packet) = replay.records.front_mut().unwrap();
+        let len = packet.len().min(buf.remaining());
+        buf.put_slice(&packet[..len]);
+        packet.drain(..len);
+        if packet.is_empty() {
+            replay.records.pop_front();
+        }
+        replay.last_offset = offset;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[test]
+fn test_record_and_replay() {
+    let path = std::env::temp_dir().join(format!("lidar_log_{}.bin", std::process::id()));
+    let mut recorder = LidarRecorder::create(
+        &path,
+        ScanFormat::DenseCapsuled,
+        Some(Duration::from_micros(125)),
+    )
+    .unwrap();
+    let start = recorder.start;
+    recorder
+        .record(start + Duration::from_millis(5), &[1, 2, 3])
+        .unwrap();
+    recorder
+        .record(start + Duration::from_millis(10), &[4, 5])
+        .unwrap();
+    recorder.flush().unwrap();
+
+    let replay = LidarReplay::open(&path, ReplayTiming::AsFastAsPossible).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(replay.format, ScanFormat::DenseCapsuled);
+    assert_eq!(replay.us_per_sample, Some(125.0));
+    assert_eq!(
+        replay.records,
+        VecDeque::from([
+            (Duration::from_millis(5), vec![1, 2, 3]),
+            (Duration::from_millis(10), vec![4, 5]),
+        ])
+    );
+
+    assert!(LidarReplay::from_bytes(b"LIDARLOG", ReplayTiming::Original).is_err());
+}
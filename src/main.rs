@@ -1,53 +1,227 @@
+mod arduino_protocol;
+mod calibration;
+mod control_loop;
+mod drive_distance;
+mod features;
+mod free_space;
 mod lidar;
+mod lidar_recording;
 mod motor_control;
 mod odometry;
 mod pose_graph;
+mod reactive_corridor;
+mod scan_filter;
+mod speed_control;
+mod steering;
 mod tcp_server;
 mod utils;
 
+use std::path::Path;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
 
-use lidar::LidarEngine;
-use motor_control::MotorControlRequest;
+use calibration::{run_calibration, CalibrationConfig};
+use control_loop::{ControlLoop, ControlLoopConfig, DriveMode, MotorChannels};
+use drive_distance::{drive_distance, DriveDistanceConfig};
+use features::{extract_features, FeatureConfig};
+use futures::StreamExt;
+use lidar::{
+    LidarConfig, LidarEngine, LidarMount, LidarScan, LidarSource, LidarStatus, MotorSpeed,
+};
+use lidar_recording::{LidarReplay, ReplayTiming};
+use motor_control::{
+    EmergencyStopReason, MotorCommand, MotorControlError, MotorControlReply, MotorControlRequest,
+    MotorLink, MotorLinkConfig, WatchdogState,
+};
+use nalgebra::Isometry2;
+use odometry::{odometry_diff, OdometryIntegrator};
 use pose_graph::PoseGraph;
+use reactive_corridor::{ReactiveCorridorConfig, ReactiveCorridorMode};
+use scan_filter::ScanFilterPipeline;
+use speed_control::{SpeedController, SpeedControllerConfig};
+use steering::SteeringCalibration;
 use tcp_server::Client;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot, watch};
 use utils::init_serialport;
 
-use tokio::io::AsyncReadExt;
 use tokio::net::{TcpListener, TcpStream};
-use tokio_serial::SerialPort;
 
 use crate::tcp_server::{CarToClient, ClientToCar};
 
 #[tokio::main]
 async fn main() {
+    // STEERING_CALIBRATION=<file> loads the steering calibration and has the calibration routine write to it,
+    // otherwise steering_calibration.txt is used, with the built in calibration until the first calibration run
+    let steering_path = std::env::var("STEERING_CALIBRATION");
+    let required = steering_path.is_ok();
+    let steering_path = steering_path.unwrap_or_else(|_| "steering_calibration.txt".to_string());
+    let steering = if required || Path::new(&steering_path).exists() {
+        match SteeringCalibration::load(&steering_path) {
+            Ok(steering) => {
+                println!("Loaded the steering calibration from {}", steering_path);
+                steering
+            }
+            Err(err) => {
+                println!(
+                    "Failed to load steering calibration {}: {}",
+                    steering_path, err
+                );
+                return;
+            }
+        }
+    } else {
+        SteeringCalibration::default()
+    };
+    // LIDAR_MOUNT=<file> loads the pose and masks of the lidar, otherwise lidar_mount.txt is used if it exists
+    let mount_path = std::env::var("LIDAR_MOUNT");
+    let required = mount_path.is_ok();
+    let mount_path = mount_path.unwrap_or_else(|_| "lidar_mount.txt".to_string());
+    let mount = if required || Path::new(&mount_path).exists() {
+        match LidarMount::load(&mount_path) {
+            Ok(mount) => {
+                println!("Loaded the lidar mount from {}", mount_path);
+                mount
+            }
+            Err(err) => {
+                println!("Failed to load lidar mount {}: {}", mount_path, err);
+                return;
+            }
+        }
+    } else {
+        // the lidar's 0 degrees points to the back of the car
+        LidarMount {
+            yaw: std::f64::consts::PI,
+            ..Default::default()
+        }
+    };
+
     // state
     let pose_graph: Arc<Mutex<PoseGraph>> = Arc::new(Mutex::new(PoseGraph::new()));
-    let motor_position = Arc::new(Mutex::new(0));
-    let servo_us = Arc::new(Mutex::new(1450));
-    let mut arduino_up = false; // if it is not up, do not send messages to it yet
-    let (tx, mut rx) = mpsc::channel::<MotorControlRequest>(32);
+    let (motor_position_tx, motor_position_rx) = watch::channel(0);
+    let (scan_tx, scan_rx) = watch::channel(None);
+    let (drive_mode_tx, drive_mode_rx) = watch::channel(DriveMode::Manual);
+    let (watchdog_tx, watchdog_rx) = watch::channel(WatchdogState::Idle);
+    let (emergency_stop_tx, emergency_stop_rx) = watch::channel(None);
+    let (pose_tx, pose_rx) = watch::channel(Isometry2::identity());
+    let drive_mode_tx = Arc::new(drive_mode_tx);
+    let servo_us = Arc::new(Mutex::new(steering.center_us));
+    let (tx, rx) = mpsc::channel::<MotorCommand>(32);
     let tx = Arc::new(tx);
 
+    // the motor control thread is spawned once everything is set up
+    let link = MotorLink::new(
+        init_serialport("/dev/ttyACM0"),
+        MotorLinkConfig {
+            servo_center: steering.center_us,
+            ..Default::default()
+        },
+        rx,
+        motor_position_tx,
+        servo_us.clone(),
+        watchdog_tx,
+        emergency_stop_tx,
+    );
+    let stop_tx = link.stop_requests();
+
+    let lidar_status = Arc::new(Mutex::new(LidarStatus::Initializing));
+
     let pose_graph_lidar_thread = pose_graph.clone();
+    let lidar_thread_status = lidar_status.clone();
+    let lidar_odometry = LidarOdometry {
+        steering: steering.clone(),
+        motor_position: motor_position_rx.clone(),
+        servo_us: servo_us.clone(),
+    };
 
-    // spawn the lidar engine on one thread
+    // spawn the lidar engine on one thread, LIDAR_MOTOR_RPM=<rpm> or LIDAR_MOTOR_PWM=<pwm> sets the rotation speed,
+    // LIDAR_REPLAY=<log> replays a recording instead of using the lidar, as fast
+    // as it can be read with LIDAR_REPLAY_FAST=1, and LIDAR_RECORD=<log> records the lidar
     tokio::spawn(async move {
-        let mut lidar_engine = LidarEngine::new(init_serialport("/dev/ttyAMA0")).await;
-        loop {
-            if let Some(scan) = lidar_engine.poll().await {
-                pose_graph_lidar_thread
-                    .lock()
-                    .unwrap()
-                    .add_node(scan.clone());
+        let lidar_config = LidarConfig {
+            mount,
+            motor_speed: lidar_motor_speed(),
+            ..Default::default()
+        };
+        if let Ok(path) = std::env::var("LIDAR_REPLAY") {
+            let timing = if std::env::var("LIDAR_REPLAY_FAST").is_ok_and(|fast| fast == "1") {
+                ReplayTiming::AsFastAsPossible
+            } else {
+                ReplayTiming::Original
+            };
+            let replay = match LidarReplay::open(&path, timing) {
+                Ok(replay) => replay,
+                Err(err) => {
+                    println!("Failed to open lidar recording {}: {}", path, err);
+                    *lidar_thread_status.lock().unwrap() = LidarStatus::Failed(err.to_string());
+                    return;
+                }
+            };
+            *lidar_thread_status.lock().unwrap() = LidarStatus::Running {
+                scan_frequency_hz: None,
+            };
+            let lidar_engine = LidarEngine::from_replay(replay, lidar_config);
+            run_lidar(
+                lidar_engine,
+                pose_graph_lidar_thread,
+                scan_tx,
+                lidar_thread_status,
+                lidar_odometry,
+            )
+            .await;
+            return;
+        }
+
+        let mut lidar_engine =
+            match LidarEngine::new(init_serialport("/dev/ttyAMA0"), lidar_config).await {
+                Ok(lidar_engine) => lidar_engine,
+                Err(err) => {
+                    println!("Lidar failed to start: {}", err);
+                    *lidar_thread_status.lock().unwrap() = LidarStatus::Failed(err.to_string());
+                    return;
+                }
+            };
+        if let Ok(path) = std::env::var("LIDAR_RECORD") {
+            match lidar_engine.record_to(&path) {
+                Ok(()) => println!("Recording lidar to {}", path),
+                Err(err) => println!("Failed to record lidar to {}: {}", path, err),
             }
         }
+        *lidar_thread_status.lock().unwrap() = LidarStatus::Running {
+            scan_frequency_hz: None,
+        };
+        run_lidar(
+            lidar_engine,
+            pose_graph_lidar_thread,
+            scan_tx,
+            lidar_thread_status,
+            lidar_odometry,
+        )
+        .await;
     });
 
     let tcp_server_tx = tx.clone();
+    let tcp_server_stop_tx = stop_tx.clone();
     let tcp_server_pose_graph = pose_graph.clone();
+    let tcp_server_lidar_status = lidar_status.clone();
+    let tcp_server_drive_mode_tx = drive_mode_tx.clone();
+    let tcp_server_watchdog_rx = watchdog_rx.clone();
+    let tcp_server_emergency_stop_rx = emergency_stop_rx.clone();
+    let tcp_server_motor_position_rx = motor_position_rx.clone();
+    let tcp_server_scan_rx = scan_rx.clone();
+    let tcp_server_pose_rx = pose_rx.clone();
+    let tcp_server_reactive_corridor_config = ReactiveCorridorConfig {
+        servo_center: steering.center_us,
+        ..Default::default()
+    };
+    let tcp_server_speed_config = SpeedControllerConfig {
+        mm_per_click: steering.mm_per_click,
+        ..Default::default()
+    };
+    let tcp_server_drive_distance_config = DriveDistanceConfig {
+        mm_per_click: steering.mm_per_click,
+        ..Default::default()
+    };
+    let tcp_server_steering = steering.clone();
     // spawn the tcp listener on another thread
     tokio::spawn(async move {
         let listener = TcpListener::bind("0.0.0.0:49925").await.unwrap();
@@ -55,21 +229,52 @@ async fn main() {
             let (stream, addr) = listener.accept().await.unwrap();
             println!("accepting connection from {}", addr);
             let client_tx = tcp_server_tx.clone();
+            let client_stop_tx = tcp_server_stop_tx.clone();
             let pose_graph_client_thread = tcp_server_pose_graph.clone();
+            let lidar_status_client_thread = tcp_server_lidar_status.clone();
+            let client_drive_mode_tx = tcp_server_drive_mode_tx.clone();
+            let client_watchdog_rx = tcp_server_watchdog_rx.clone();
+            let client_emergency_stop_rx = tcp_server_emergency_stop_rx.clone();
+            let client_motor_position_rx = tcp_server_motor_position_rx.clone();
+            let client_scan_rx = tcp_server_scan_rx.clone();
+            let client_pose_rx = tcp_server_pose_rx.clone();
+            let client_reactive_corridor_config = tcp_server_reactive_corridor_config.clone();
+            let client_speed_config = tcp_server_speed_config.clone();
+            let client_drive_distance_config = tcp_server_drive_distance_config.clone();
+            let client_steering = tcp_server_steering.clone();
+            let client_steering_path = steering_path.clone();
 
             // give each client its own green thread
             tokio::spawn(async move {
                 let mut client = Client::new(stream);
+                // distance moves and motor requests finish in the background and report back here
+                let (reply_tx, mut reply_rx) = mpsc::channel::<CarToClient<'static>>(16);
 
                 loop {
+                    let packets = tokio::select! {
+                        packets = client.poll() => packets,
+                        Some(reply) = reply_rx.recv() => {
+                            reply.write(&mut client.stream).await.unwrap();
+                            continue;
+                        }
+                    };
                     // handle client loop
-                    if let Ok(packets) = client.poll().await {
+                    if let Ok(packets) = packets {
                         println!("read {} packets", packets.len());
                         for packet in packets {
                             println!("received packet {:?}", packet);
+                            let id = packet.id();
                             match packet {
                                 ClientToCar::GetCurrentPose => {
-                                    // cry about it
+                                    let pose = *client_pose_rx.borrow();
+                                    CarToClient::CurrentPose {
+                                        x: pose.translation.x as f32,
+                                        y: pose.translation.y as f32,
+                                        theta: pose.rotation.angle() as f32,
+                                    }
+                                    .write(&mut client.stream)
+                                    .await
+                                    .unwrap();
                                 }
                                 ClientToCar::GetMostRecentLidarScan => {
                                     let scan = {
@@ -87,18 +292,147 @@ async fn main() {
                                             .unwrap();
                                     }
                                 }
-                                ClientToCar::SetServoPosition { microseconds } => {
-                                    client_tx
-                                        .try_send(MotorControlRequest::SetServoPosition {
-                                            microseconds,
-                                        })
+                                ClientToCar::GetScanFeatures => {
+                                    let features = pose_graph_client_thread
+                                        .lock()
+                                        .unwrap()
+                                        .nodes
+                                        .last()
+                                        .map(|node| node.features.clone());
+                                    if let Some(features) = features {
+                                        CarToClient::ScanFeatures {
+                                            features: &features,
+                                        }
+                                        .write(&mut client.stream)
+                                        .await
                                         .unwrap();
+                                    }
+                                }
+                                ClientToCar::SetServoPosition { microseconds } => {
+                                    send_motor_request(
+                                        &client_tx,
+                                        MotorControlRequest::SetServoPosition { microseconds },
+                                        id,
+                                        &reply_tx,
+                                    );
                                 }
                                 ClientToCar::SetMotorOutput(output) => {
-                                    client_tx
-                                        .try_send(MotorControlRequest::SetMotorOutput(output))
+                                    send_motor_request(
+                                        &client_tx,
+                                        MotorControlRequest::SetMotorOutput(output),
+                                        id,
+                                        &reply_tx,
+                                    );
+                                }
+                                ClientToCar::GetLidarStatus => {
+                                    let status = lidar_status_client_thread.lock().unwrap().clone();
+                                    CarToClient::LidarStatus { status: &status }
+                                        .write(&mut client.stream)
+                                        .await
+                                        .unwrap();
+                                }
+                                ClientToCar::StartReactiveCorridor => {
+                                    client_drive_mode_tx.send_replace(DriveMode::ReactiveCorridor(
+                                        ReactiveCorridorMode::new(
+                                            client_reactive_corridor_config.clone(),
+                                        ),
+                                    ));
+                                }
+                                ClientToCar::SetTargetSpeed { mm_per_s } => {
+                                    client_drive_mode_tx.send_replace(DriveMode::Speed(
+                                        SpeedController::new(
+                                            client_speed_config.clone(),
+                                            mm_per_s as f64,
+                                        ),
+                                    ));
+                                }
+                                ClientToCar::StopDriveMode => {
+                                    client_drive_mode_tx.send_replace(DriveMode::Manual);
+                                }
+                                ClientToCar::GetWatchdogStatus => {
+                                    let state = *client_watchdog_rx.borrow();
+                                    CarToClient::WatchdogStatus { state }
+                                        .write(&mut client.stream)
+                                        .await
+                                        .unwrap();
+                                }
+                                ClientToCar::EmergencyStop => {
+                                    send_stop_request(
+                                        &client_stop_tx,
+                                        MotorControlRequest::EmergencyStop(
+                                            EmergencyStopReason::Client,
+                                        ),
+                                        id,
+                                        &reply_tx,
+                                    );
+                                }
+                                ClientToCar::ResetEmergencyStop => {
+                                    send_stop_request(
+                                        &client_stop_tx,
+                                        MotorControlRequest::ResetEmergencyStop,
+                                        id,
+                                        &reply_tx,
+                                    );
+                                }
+                                ClientToCar::GetEmergencyStopStatus => {
+                                    let reason = *client_emergency_stop_rx.borrow();
+                                    CarToClient::EmergencyStopStatus { reason }
+                                        .write(&mut client.stream)
+                                        .await
                                         .unwrap();
                                 }
+                                ClientToCar::DriveDistance { millimeters } => {
+                                    // the control loop would fight the move over the motor
+                                    client_drive_mode_tx.send_replace(DriveMode::Manual);
+                                    let client_tx = client_tx.clone();
+                                    let motor_position_rx = client_motor_position_rx.clone();
+                                    let reply_tx = reply_tx.clone();
+                                    let config = client_drive_distance_config.clone();
+                                    tokio::spawn(async move {
+                                        let result = drive_distance(
+                                            &client_tx,
+                                            motor_position_rx,
+                                            millimeters,
+                                            &config,
+                                        )
+                                        .await;
+                                        if let Err(err) = &result {
+                                            println!("driving {}mm failed: {}", millimeters, err);
+                                        }
+                                        let _ = reply_tx
+                                            .send(CarToClient::DriveDistanceResult { result })
+                                            .await;
+                                    });
+                                }
+                                ClientToCar::StartCalibration => {
+                                    // the control loop would fight the calibration over the motor
+                                    client_drive_mode_tx.send_replace(DriveMode::Manual);
+                                    let client_tx = client_tx.clone();
+                                    let motor_position_rx = client_motor_position_rx.clone();
+                                    let scan_rx = client_scan_rx.clone();
+                                    let steering = client_steering.clone();
+                                    let path = client_steering_path.clone();
+                                    tokio::spawn(async move {
+                                        let calibration = run_calibration(
+                                            &CalibrationConfig::default(),
+                                            &steering,
+                                            &client_tx,
+                                            motor_position_rx,
+                                            scan_rx,
+                                        )
+                                        .await;
+                                        match calibration.map(|calibration| calibration.save(&path)) {
+                                            Ok(Ok(())) => println!(
+                                                "Saved the calibration to {}, it is used from the next start",
+                                                path
+                                            ),
+                                            Ok(Err(err)) => {
+                                                println!("Failed to save the calibration to {}: {}", path, err)
+                                            }
+                                            Err(err) => println!("Calibration failed: {}", err),
+                                        }
+                                    });
+                                }
                             }
                         }
                     }
@@ -107,82 +441,153 @@ async fn main() {
         }
     });
 
-    // spawn the motor control thread
-    let thread_motor_position = motor_position.clone();
-    let thread_servo_us = servo_us.clone();
+    // dead reckon from the encoder position and the servo position the motor task reports
+    let mut odometry = OdometryIntegrator::new(steering.clone());
+    let mut odometry_motor_position_rx = motor_position_rx.clone();
+    let odometry_servo_us = servo_us.clone();
     tokio::spawn(async move {
-        let mut arduino_port = init_serialport("/dev/ttyACM0");
+        while odometry_motor_position_rx.changed().await.is_ok() {
+            let position = *odometry_motor_position_rx.borrow_and_update();
+            odometry.update(position, *odometry_servo_us.lock().unwrap());
+            pose_tx.send_replace(odometry.pose);
+        }
+    });
 
-        loop {
-            if arduino_up {
-                if let Ok(request) = rx.try_recv() {
-                    println!("sending request: {:?}", request);
-                    if let MotorControlRequest::SetServoPosition { microseconds } = request {
-                        *thread_servo_us.lock().unwrap() = microseconds;
-                    }
-                    request.write(&mut arduino_port).await.unwrap();
-                }
+    // spawn the motor control thread
+    tokio::spawn(link.run());
+
+    ControlLoop::new(
+        ControlLoopConfig {
+            steering: steering.clone(),
+            ..Default::default()
+        },
+        drive_mode_rx,
+        scan_rx,
+        MotorChannels {
+            commands: (*tx).clone(),
+            stops: stop_tx,
+            motor_position: motor_position_rx,
+            servo_us,
+            emergency_stop: emergency_stop_rx,
+        },
+    )
+    .run()
+    .await;
+}
+
+/// Rotation speed of the lidar from LIDAR_MOTOR_RPM or LIDAR_MOTOR_PWM, None leaves the lidar at its default speed
+fn lidar_motor_speed() -> Option<MotorSpeed> {
+    let parse = |name: &str| {
+        let value = std::env::var(name).ok()?;
+        match value.parse() {
+            Ok(value) => Some(value),
+            Err(err) => {
+                println!("Ignoring {}={}: {}", name, value, err);
+                None
             }
-            // 5 byte packet sent over and over again: 0b10101010, i32::to_le_bytes()
-            if arduino_port.bytes_to_read().unwrap() >= 5 {
-                arduino_up = true;
-                let mut buffer = [0; 5];
-                arduino_port.read_exact(&mut buffer).await.unwrap();
-                // the first byte of the packet, think of it as a flag
-                if buffer[0] == 0b10101010 {
-                    // this could *technically* return an invalid motor position if the alignment of the packet is off. This could only reasonably happen if the arduino somehow starts at a position where the first byte is 0b10101010, which is not a normal starting configuration
-                    let position = i32::from_le_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
-                    *thread_motor_position.lock().unwrap() = position;
-                } else {
-                    // alignment is off of the 5 byte packet
-                    arduino_port.read_exact(&mut [0; 1]).await.unwrap();
-                }
+        }
+    };
+    parse("LIDAR_MOTOR_RPM")
+        .map(MotorSpeed::Rpm)
+        .or_else(|| parse("LIDAR_MOTOR_PWM").map(MotorSpeed::Pwm))
+}
+
+/// What the lidar task needs to know how the car moved during a scan
+struct LidarOdometry {
+    steering: SteeringCalibration,
+    motor_position: watch::Receiver<i32>,
+    servo_us: Arc<Mutex<u16>>,
+}
+
+/// Filter every scan of the lidar, hand it to the control loop and add it deskewed to the pose graph until the lidar
+/// stops streaming
+async fn run_lidar<P: LidarSource>(
+    mut lidar_engine: LidarEngine<P>,
+    pose_graph: Arc<Mutex<PoseGraph>>,
+    scan_tx: watch::Sender<Option<Arc<LidarScan>>>,
+    status: Arc<Mutex<LidarStatus>>,
+    odometry: LidarOdometry,
+) {
+    let scan_filters = ScanFilterPipeline::default();
+    let feature_config = FeatureConfig::default();
+    let mut last_position = None;
+    while let Some(scan) = lidar_engine.next().await {
+        match scan {
+            Ok(mut scan) => {
+                *status.lock().unwrap() = LidarStatus::Running {
+                    scan_frequency_hz: lidar_engine.scan_frequency_hz(),
+                };
+                scan_filters.apply(&mut scan);
+                scan_tx.send_replace(Some(Arc::new(scan.clone())));
+                // the scans follow each other, so the car drove the clicks since the last one during this one
+                let position = *odometry.motor_position.borrow();
+                let clicks = last_position
+                    .replace(position)
+                    .map_or(0, |last| position - last);
+                let motion = odometry_diff(
+                    &odometry.steering,
+                    *odometry.servo_us.lock().unwrap(),
+                    clicks,
+                );
+                // extracted before locking, the clients wait on the pose graph
+                let features = extract_features(&scan.deskew(&motion), &feature_config);
+                pose_graph.lock().unwrap().add_node(scan, features);
+            }
+            Err(err) => {
+                println!(
+                    "Lidar error: {} ({} packets dropped so far)",
+                    err, lidar_engine.dropped_packets
+                );
             }
         }
-    });
+    }
+    println!("Lidar stopped streaming");
+}
 
-    tx.send(MotorControlRequest::SetServoPosition { microseconds: 1450 })
-        .await
-        .unwrap();
-
-    loop {
-        // fixme this is not efficient
-        // if let Some(node) = pose_graph.lock().unwrap().nodes.last() {
-        //     let scan = &node.scan;
-        //     let raycasts = scan.raycasts();
-        //     let left_right = (raycasts[6] as i32 - raycasts[2] as i32) / 2;
-        //     // let front_dist = raycasts[3].min(raycasts[4]).min(raycasts[5]);
-        //     // let back_dist = raycasts[7].min(raycasts[0]).min(raycasts[1]);
-        //     let front_dist = raycasts[4];
-        //     let back_dist = raycasts[0];
-        //     dbg!(front_dist, back_dist);
-        //     if direction == Direction::Forward {
-        //         if front_dist < 1500 {
-        //             direction = Direction::Backward;
-        //             tx.send(MotorControlRequest::SetMotorOutput(-220))
-        //                 .await
-        //                 .unwrap();
-        //         }
-        //     } else {
-        //         if back_dist < 750 {
-        //             direction = Direction::Forward;
-        //             tx.send(MotorControlRequest::SetMotorOutput(220))
-        //                 .await
-        //                 .unwrap();
-        //         }
-        //     }
-
-        //     let steering;
-        //     if direction == Direction::Forward {
-        //         steering = (left_right.max(-350).min(350) + 1450) as u16;
-        //     } else {
-        //         steering = (-left_right.max(-350).min(350) + 1450) as u16;
-        //     }
-        //     tx.send(MotorControlRequest::SetServoPosition {
-        //         microseconds: steering,
-        //     })
-        //     .await
-        //     .unwrap();
-        // }
+/// Queue a request from a client for the motor task and tell the client whether the Arduino applied it
+fn send_motor_request(
+    tx: &mpsc::Sender<MotorCommand>,
+    request: MotorControlRequest,
+    id: u8,
+    replies: &mpsc::Sender<CarToClient<'static>>,
+) {
+    let (command, reply) = MotorCommand::with_reply(request);
+    // a dropped command reports the link as down
+    if let Err(err) = tx.try_send(command) {
+        println!("motor task is not taking requests: {}", err);
     }
+    forward_motor_reply(reply, id, replies);
+}
+
+/// Like [`send_motor_request`] for emergency stops, which go around the command queue
+fn send_stop_request(
+    tx: &mpsc::UnboundedSender<MotorCommand>,
+    request: MotorControlRequest,
+    id: u8,
+    replies: &mpsc::Sender<CarToClient<'static>>,
+) {
+    let (command, reply) = MotorCommand::with_reply(request);
+    if let Err(err) = tx.send(command) {
+        println!("motor task stopped, can't send {:?}", err.0.request);
+    }
+    forward_motor_reply(reply, id, replies);
+}
+
+/// Send the client the reply to its motor request once it arrives
+fn forward_motor_reply(
+    reply: oneshot::Receiver<MotorControlReply>,
+    id: u8,
+    replies: &mpsc::Sender<CarToClient<'static>>,
+) {
+    let replies = replies.clone();
+    tokio::spawn(async move {
+        // the motor task dropping the request means it is gone
+        let reply = reply.await.unwrap_or(Err(MotorControlError::LinkDown));
+        if let Err(err) = &reply {
+            println!("client motor request failed: {}", err);
+        }
+        let _ = replies
+            .send(CarToClient::MotorReply { request: id, reply })
+            .await;
+    });
 }
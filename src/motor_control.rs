@@ -1,8 +1,57 @@
-use tokio::io::AsyncWriteExt;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::Instant;
 use tokio_serial::SerialStream;
 
+use crate::arduino_protocol::{ArduinoMessage, Frame, FrameDecoder};
+use crate::steering::SteeringCalibration;
+
+/// How long to wait for the Arduino to acknowledge a command before sending it again
+const ACK_TIMEOUT: Duration = Duration::from_millis(50);
+/// How many times a command is sent before giving up on it
+const MAX_ATTEMPTS: u32 = 3;
+/// Car to Arduino: the Pi is still alive, the firmware stops the car if these stop coming
+const MESSAGE_HEARTBEAT: u8 = 0x04;
+
+#[derive(Debug, Clone)]
+pub struct MotorLinkConfig {
+    /// Stop the car if it is driving and no command arrived for this long
+    pub command_timeout: Duration,
+    /// How often a heartbeat is sent to the Arduino
+    pub heartbeat_interval: Duration,
+    /// Servo position for driving straight, set as soon as the Arduino is up and whenever the car is stopped
+    pub servo_center: u16,
+}
+
+impl Default for MotorLinkConfig {
+    fn default() -> Self {
+        Self {
+            command_timeout: Duration::from_millis(500),
+            heartbeat_interval: Duration::from_millis(100),
+            servo_center: SteeringCalibration::default().center_us,
+        }
+    }
+}
+
+/// Whether the watchdog of the motor task is waiting to stop the car
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogState {
+    /// The motor isn't driving, so there's nothing to stop
+    Idle,
+    /// The car is driving and will be stopped unless a new command arrives in time
+    Armed,
+    /// The car was stopped because the commands stopped coming
+    Tripped,
+}
+
 #[derive(Debug)]
 pub enum MotorControlRequest {
+    /// Encoder position in clicks for the Arduino to drive the motor to, on the same scale as the reported position
     SetMotorPosition {
         clicks: i32,
     },
@@ -12,25 +61,730 @@ pub enum MotorControlRequest {
     },
     /// Values between -255 and 255 are allowed. Negative values are reverse, positive values are forward.
     SetMotorOutput(i16),
+    /// Stop the motor right away and reject motion commands until the stop is reset. Handled by the motor task, which
+    /// sends the Arduino the commands to stop.
+    EmergencyStop(EmergencyStopReason),
+    ResetEmergencyStop,
+}
+
+/// Why the car was emergency stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmergencyStopReason {
+    /// A client asked for it
+    Client,
+    /// Something came within this many millimeters of the car while it was driving
+    Proximity { distance: f64 },
+    /// The commands stopped coming while the car was driving
+    Watchdog,
 }
 
 impl MotorControlRequest {
-    pub async fn write(&self, port: &mut SerialStream) -> Result<(), tokio_serial::Error> {
+    /// The message type followed by the little endian value, None for requests handled by the motor task itself
+    pub fn to_payload(&self) -> Option<Vec<u8>> {
+        let mut payload = Vec::with_capacity(5);
         match self {
             MotorControlRequest::SetMotorPosition { clicks } => {
-                port.write(&[0x01]).await?;
-                let bytes = clicks.to_le_bytes();
-                port.write(&bytes).await?;
+                payload.push(0x01);
+                payload.extend_from_slice(&clicks.to_le_bytes());
             }
             MotorControlRequest::SetServoPosition { microseconds } => {
-                port.write(&[0x02]).await?;
-                port.write(&microseconds.to_le_bytes()).await?;
+                payload.push(0x02);
+                payload.extend_from_slice(&microseconds.to_le_bytes());
             }
             MotorControlRequest::SetMotorOutput(output) => {
-                port.write(&[0x03]).await?;
-                port.write(&output.to_le_bytes()).await?;
+                payload.push(0x03);
+                payload.extend_from_slice(&output.to_le_bytes());
+            }
+            MotorControlRequest::EmergencyStop(_) | MotorControlRequest::ResetEmergencyStop => {
+                return None;
             }
         }
+        Some(payload)
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(
+        &self,
+        port: &mut W,
+        seq: u8,
+    ) -> Result<(), std::io::Error> {
+        let Some(payload) = self.to_payload() else {
+            return Ok(());
+        };
+        let frame = Frame { seq, payload };
+        port.write_all(&frame.encode()).await?;
         Ok(())
     }
+
+    /// Whether the request would move the car
+    fn is_motion(&self) -> bool {
+        match self {
+            MotorControlRequest::SetMotorPosition { .. } => true,
+            MotorControlRequest::SetMotorOutput(output) => *output != 0,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MotorControlError {
+    /// The Arduino hasn't sent anything yet, so the command wasn't sent
+    LinkDown,
+    /// The Arduino didn't acknowledge the command
+    Timeout {
+        attempts: u32,
+    },
+    /// The car is emergency stopped, so it may not move
+    EmergencyStopped(EmergencyStopReason),
+    /// A newer command of the same kind replaced it before it was sent
+    Superseded,
+    Io(std::io::Error),
+}
+
+impl fmt::Display for MotorControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MotorControlError::LinkDown => write!(f, "the arduino link is not up"),
+            MotorControlError::Timeout { attempts } => {
+                write!(f, "no acknowledgement after {} attempts", attempts)
+            }
+            MotorControlError::EmergencyStopped(reason) => {
+                write!(f, "emergency stopped ({:?})", reason)
+            }
+            MotorControlError::Superseded => write!(f, "replaced by a newer command"),
+            MotorControlError::Io(err) => write!(f, "serial error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MotorControlError {}
+
+/// The value the Arduino applied, or why the request didn't make it
+pub type MotorControlReply = Result<i32, MotorControlError>;
+
+/// A request for the motor task, optionally with a channel to hear back on
+#[derive(Debug)]
+pub struct MotorCommand {
+    pub request: MotorControlRequest,
+    pub reply: Option<oneshot::Sender<MotorControlReply>>,
+}
+
+impl MotorCommand {
+    /// A command along with the receiver of its reply
+    pub fn with_reply(
+        request: MotorControlRequest,
+    ) -> (Self, oneshot::Receiver<MotorControlReply>) {
+        let (reply, receiver) = oneshot::channel();
+        (
+            MotorCommand {
+                request,
+                reply: Some(reply),
+            },
+            receiver,
+        )
+    }
+
+    fn finish(self, result: MotorControlReply) {
+        match self.reply {
+            // the sender may not be waiting for the reply anymore
+            Some(reply) => {
+                let _ = reply.send(result);
+            }
+            None => match result {
+                // the control loop replacing its own setpoints while the link is slow is expected
+                Ok(_) | Err(MotorControlError::Superseded) => {}
+                Err(err) => println!("motor request {:?} failed: {}", self.request, err),
+            },
+        }
+    }
+}
+
+impl From<MotorControlRequest> for MotorCommand {
+    fn from(request: MotorControlRequest) -> Self {
+        MotorCommand {
+            request,
+            reply: None,
+        }
+    }
+}
+
+/// A command sent to the Arduino which hasn't been acknowledged yet
+struct InFlight {
+    command: MotorCommand,
+    seq: u8,
+    attempts: u32,
+    deadline: Instant,
+}
+
+/// Talks to the Arduino: sends the commands one at a time, retrying until they are acknowledged, and publishes the
+/// encoder position
+pub struct MotorLink<P = SerialStream> {
+    port: P,
+    pub config: MotorLinkConfig,
+    commands: mpsc::Receiver<MotorCommand>,
+    /// Emergency stops and their resets, handled before anything else and never dropped for a full channel
+    stop_requests: mpsc::UnboundedReceiver<MotorCommand>,
+    stop_requests_tx: mpsc::UnboundedSender<MotorCommand>,
+    motor_position: watch::Sender<i32>,
+    servo_us: Arc<Mutex<u16>>,
+    decoder: FrameDecoder,
+    queue: VecDeque<MotorCommand>,
+    in_flight: Option<InFlight>,
+    seq: u8,
+    /// Set once the Arduino sends its first frame, commands fail right away before that
+    arduino_up: bool,
+    /// When the last command arrived over the channel
+    last_command: Instant,
+    watchdog: watch::Sender<WatchdogState>,
+    /// Why the car is latched in an emergency stop, None if it isn't
+    emergency_stop: watch::Sender<Option<EmergencyStopReason>>,
+}
+
+impl<P: AsyncRead + AsyncWrite + Unpin> MotorLink<P> {
+    pub fn new(
+        port: P,
+        config: MotorLinkConfig,
+        commands: mpsc::Receiver<MotorCommand>,
+        motor_position: watch::Sender<i32>,
+        servo_us: Arc<Mutex<u16>>,
+        watchdog: watch::Sender<WatchdogState>,
+        emergency_stop: watch::Sender<Option<EmergencyStopReason>>,
+    ) -> Self {
+        let (stop_requests_tx, stop_requests) = mpsc::unbounded_channel();
+        MotorLink {
+            port,
+            config,
+            commands,
+            stop_requests,
+            stop_requests_tx,
+            motor_position,
+            servo_us,
+            decoder: FrameDecoder::new(),
+            queue: VecDeque::new(),
+            in_flight: None,
+            seq: 0,
+            arduino_up: false,
+            last_command: Instant::now(),
+            watchdog,
+            emergency_stop,
+        }
+    }
+
+    /// Sender for emergency stops, which must not wait behind other commands or get dropped when the command channel
+    /// is full
+    pub fn stop_requests(&self) -> mpsc::UnboundedSender<MotorCommand> {
+        self.stop_requests_tx.clone()
+    }
+
+    pub async fn run(mut self) {
+        let mut bytes = [0; 64];
+        let mut heartbeat = tokio::time::interval(self.config.heartbeat_interval);
+        heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            let deadline = self.in_flight.as_ref().map(|in_flight| in_flight.deadline);
+            tokio::select! {
+                biased;
+                Some(command) = self.stop_requests.recv() => self.receive(command),
+                Some(command) = self.commands.recv() => self.receive(command),
+                read = self.port.read(&mut bytes) => {
+                    match read {
+                        Ok(0) | Err(_) => {
+                            println!("arduino link closed: {:?}", read);
+                            return;
+                        }
+                        Ok(read) => {
+                            self.decoder.push(&bytes[..read]);
+                            self.handle_frames();
+                        }
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.retry().await;
+                }
+                _ = heartbeat.tick() => {
+                    self.check_watchdog();
+                    self.send_heartbeat().await;
+                }
+            }
+            if self.in_flight.is_none() {
+                if let Some(command) = self.queue.pop_front() {
+                    self.send(command).await;
+                }
+            }
+        }
+    }
+
+    fn handle_frames(&mut self) {
+        let crc_failures = self.decoder.crc_failures;
+        while let Some(frame) = self.decoder.next_frame() {
+            if !self.arduino_up {
+                self.arduino_up = true;
+                println!("arduino link is up");
+                self.queue.push_back(
+                    MotorControlRequest::SetServoPosition {
+                        microseconds: self.config.servo_center,
+                    }
+                    .into(),
+                );
+            }
+            match ArduinoMessage::from_payload(&frame.payload) {
+                Some(ArduinoMessage::EncoderPosition(position)) => {
+                    self.motor_position.send_replace(position);
+                }
+                Some(ArduinoMessage::Ack { seq, value }) => self.acknowledge(seq, value),
+                None => println!("unknown message from the arduino: {:?}", frame.payload),
+            }
+        }
+        if self.decoder.crc_failures != crc_failures {
+            println!(
+                "arduino link: {} frames failed their CRC so far",
+                self.decoder.crc_failures
+            );
+        }
+    }
+
+    fn receive(&mut self, command: MotorCommand) {
+        match command.request {
+            MotorControlRequest::EmergencyStop(reason) => {
+                self.stop(reason);
+                command.finish(Ok(0));
+                return;
+            }
+            MotorControlRequest::ResetEmergencyStop => {
+                if let Some(reason) = self.emergency_stop.send_replace(None) {
+                    println!("emergency stop ({:?}) reset", reason);
+                }
+                self.watchdog.send_replace(WatchdogState::Idle);
+                command.finish(Ok(0));
+                return;
+            }
+            _ => {}
+        }
+        if let Some(reason) = *self.emergency_stop.borrow() {
+            if command.request.is_motion() {
+                command.finish(Err(MotorControlError::EmergencyStopped(reason)));
+                return;
+            }
+        }
+        // rejected commands don't count, they would keep the watchdog from noticing a sender gone quiet
+        self.last_command = Instant::now();
+        if !self.arduino_up {
+            command.finish(Err(MotorControlError::LinkDown));
+            return;
+        }
+        if let MotorControlRequest::SetMotorOutput(output) = command.request {
+            let state = if output == 0 {
+                WatchdogState::Idle
+            } else {
+                WatchdogState::Armed
+            };
+            self.watchdog.send_replace(state);
+        }
+        self.enqueue(command);
+    }
+
+    /// Queue a command, replacing a queued motor output or servo position with the newer one. Only one command is in
+    /// flight, so the older setpoints would be stale by the time they are sent.
+    fn enqueue(&mut self, command: MotorCommand) {
+        let same_kind = |queued: &MotorCommand| {
+            matches!(
+                (&queued.request, &command.request),
+                (
+                    MotorControlRequest::SetMotorOutput(_),
+                    MotorControlRequest::SetMotorOutput(_)
+                ) | (
+                    MotorControlRequest::SetServoPosition { .. },
+                    MotorControlRequest::SetServoPosition { .. }
+                )
+            )
+        };
+        match self.queue.iter_mut().find(|queued| same_kind(queued)) {
+            Some(queued) => {
+                std::mem::replace(queued, command).finish(Err(MotorControlError::Superseded))
+            }
+            None => self.queue.push_back(command),
+        }
+    }
+
+    /// Latch the emergency stop, dropping every queued or unacknowledged motion command and stopping the motor before
+    /// anything else
+    fn stop(&mut self, reason: EmergencyStopReason) {
+        if let Some(latched) = *self.emergency_stop.borrow() {
+            println!(
+                "emergency stop ({:?}) while already stopped ({:?})",
+                reason, latched
+            );
+            return;
+        }
+        println!("EMERGENCY STOP: {:?}", reason);
+        self.emergency_stop.send_replace(Some(reason));
+        // the motor is stopped on purpose, the watchdog mustn't trip on the quiet that follows
+        if reason != EmergencyStopReason::Watchdog {
+            self.watchdog.send_replace(WatchdogState::Idle);
+        }
+        let queue = std::mem::take(&mut self.queue);
+        for command in queue {
+            if command.request.is_motion() {
+                command.finish(Err(MotorControlError::EmergencyStopped(reason)));
+            } else {
+                self.queue.push_back(command);
+            }
+        }
+        // the stop goes out right away instead of waiting for the command in flight to be acknowledged or time out,
+        // whose acks are ignored from now on. Anything but motion is sent again after the stop.
+        if let Some(in_flight) = self.in_flight.take() {
+            if in_flight.command.request.is_motion() {
+                in_flight
+                    .command
+                    .finish(Err(MotorControlError::EmergencyStopped(reason)));
+            } else {
+                self.queue.push_front(in_flight.command);
+            }
+        }
+        self.queue.push_front(
+            MotorControlRequest::SetServoPosition {
+                microseconds: self.config.servo_center,
+            }
+            .into(),
+        );
+        self.queue
+            .push_front(MotorControlRequest::SetMotorOutput(0).into());
+    }
+
+    /// Stop the car if it is driving on a command which is too old
+    fn check_watchdog(&mut self) {
+        if *self.watchdog.borrow() != WatchdogState::Armed
+            || self.last_command.elapsed() < self.config.command_timeout
+        {
+            return;
+        }
+        println!(
+            "motor watchdog: no command for {:?}, stopping the car",
+            self.last_command.elapsed()
+        );
+        self.watchdog.send_replace(WatchdogState::Tripped);
+        self.stop(EmergencyStopReason::Watchdog);
+    }
+
+    async fn send_heartbeat(&mut self) {
+        if !self.arduino_up {
+            return;
+        }
+        let frame = Frame {
+            seq: self.seq,
+            payload: vec![MESSAGE_HEARTBEAT],
+        };
+        self.seq = self.seq.wrapping_add(1);
+        if let Err(err) = self.port.write_all(&frame.encode()).await {
+            println!("failed to send a heartbeat to the arduino: {}", err);
+        }
+    }
+
+    fn acknowledge(&mut self, seq: u8, value: i32) {
+        // acks of earlier attempts of an already finished command are ignored
+        if self
+            .in_flight
+            .as_ref()
+            .is_none_or(|in_flight| in_flight.seq != seq)
+        {
+            return;
+        }
+        let in_flight = self.in_flight.take().unwrap();
+        if let MotorControlRequest::SetServoPosition { .. } = in_flight.command.request {
+            *self.servo_us.lock().unwrap() = value as u16;
+        }
+        in_flight.command.finish(Ok(value));
+    }
+
+    async fn send(&mut self, command: MotorCommand) {
+        println!("sending request: {:?}", command.request);
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        if let Err(err) = command.request.write(&mut self.port, seq).await {
+            command.finish(Err(MotorControlError::Io(err)));
+            return;
+        }
+        self.in_flight = Some(InFlight {
+            command,
+            seq,
+            attempts: 1,
+            deadline: Instant::now() + ACK_TIMEOUT,
+        });
+    }
+
+    /// Send the command in flight again with the same sequence number, or give up on it
+    async fn retry(&mut self) {
+        let Some(mut in_flight) = self.in_flight.take() else {
+            return;
+        };
+        if in_flight.attempts >= MAX_ATTEMPTS {
+            in_flight.command.finish(Err(MotorControlError::Timeout {
+                attempts: in_flight.attempts,
+            }));
+            return;
+        }
+        if let Err(err) = in_flight
+            .command
+            .request
+            .write(&mut self.port, in_flight.seq)
+            .await
+        {
+            in_flight.command.finish(Err(MotorControlError::Io(err)));
+            return;
+        }
+        in_flight.attempts += 1;
+        in_flight.deadline = Instant::now() + ACK_TIMEOUT;
+        self.in_flight = Some(in_flight);
+    }
+}
+
+/// A link spawned on one end of an in-memory stream, with the test playing the Arduino on the other end
+#[cfg(test)]
+struct TestLink {
+    commands: mpsc::Sender<MotorCommand>,
+    stops: mpsc::UnboundedSender<MotorCommand>,
+    motor_position: watch::Receiver<i32>,
+    servo_us: Arc<Mutex<u16>>,
+    watchdog: watch::Receiver<WatchdogState>,
+    emergency_stop: watch::Receiver<Option<EmergencyStopReason>>,
+    arduino: tokio::io::DuplexStream,
+    decoder: FrameDecoder,
+    seq: u8,
+}
+
+#[cfg(test)]
+impl TestLink {
+    fn spawn(config: MotorLinkConfig) -> Self {
+        let (port, arduino) = tokio::io::duplex(256);
+        let (commands, rx) = mpsc::channel(4);
+        let (motor_position_tx, motor_position) = watch::channel(0);
+        let servo_us = Arc::new(Mutex::new(0));
+        let (watchdog_tx, watchdog) = watch::channel(WatchdogState::Idle);
+        let (emergency_stop_tx, emergency_stop) = watch::channel(None);
+        let link = MotorLink::new(
+            port,
+            config,
+            rx,
+            motor_position_tx,
+            servo_us.clone(),
+            watchdog_tx,
+            emergency_stop_tx,
+        );
+        let stops = link.stop_requests();
+        tokio::spawn(link.run());
+        TestLink {
+            commands,
+            stops,
+            motor_position,
+            servo_us,
+            watchdog,
+            emergency_stop,
+            arduino,
+            decoder: FrameDecoder::new(),
+            seq: 0,
+        }
+    }
+
+    /// Send a command over the command channel, returning the receiver of its reply
+    async fn request(&self, request: MotorControlRequest) -> oneshot::Receiver<MotorControlReply> {
+        let (command, reply) = MotorCommand::with_reply(request);
+        self.commands.send(command).await.unwrap();
+        reply
+    }
+
+    async fn write(&mut self, message: ArduinoMessage) {
+        let frame = Frame {
+            seq: self.seq,
+            payload: message.to_payload(),
+        };
+        self.seq = self.seq.wrapping_add(1);
+        self.arduino.write_all(&frame.encode()).await.unwrap();
+    }
+
+    /// Report the encoder position and wait for the link to publish it, the link is up after the first one
+    async fn send_position(&mut self, position: i32) {
+        self.write(ArduinoMessage::EncoderPosition(position)).await;
+        self.motor_position.changed().await.unwrap();
+    }
+
+    async fn ack(&mut self, seq: u8, value: i32) {
+        self.write(ArduinoMessage::Ack { seq, value }).await;
+    }
+
+    /// The next frame the link sends, heartbeats included
+    async fn next_frame(&mut self) -> Frame {
+        let mut bytes = [0; 64];
+        loop {
+            if let Some(frame) = self.decoder.next_frame() {
+                return frame;
+            }
+            let read = self.arduino.read(&mut bytes).await.unwrap();
+            self.decoder.push(&bytes[..read]);
+        }
+    }
+
+    /// The next frame the link sends which isn't a heartbeat
+    async fn next_command(&mut self) -> Frame {
+        loop {
+            let frame = self.next_frame().await;
+            if frame.payload != [MESSAGE_HEARTBEAT] {
+                return frame;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_motor_link_retries_until_acknowledged() {
+    let mut link = TestLink::spawn(MotorLinkConfig::default());
+
+    // commands fail until the arduino has said something
+    let reply = link.request(MotorControlRequest::SetMotorOutput(100)).await;
+    assert!(matches!(reply.await, Ok(Err(MotorControlError::LinkDown))));
+
+    link.send_position(42).await;
+    let reply = link
+        .request(MotorControlRequest::SetServoPosition { microseconds: 1600 })
+        .await;
+
+    // the link centers the servo once it is up, and the first attempt of the command goes unanswered
+    let center = link.next_command().await;
+    link.ack(center.seq, MotorLinkConfig::default().servo_center as i32)
+        .await;
+    let first = link.next_command().await;
+    let retry = link.next_command().await;
+    assert_eq!(first.seq, retry.seq);
+    assert_eq!(retry.payload, vec![0x02, 0x40, 0x06]);
+    link.ack(retry.seq, 1590).await;
+
+    assert_eq!(reply.await.unwrap().unwrap(), 1590);
+    assert_eq!(*link.servo_us.lock().unwrap(), 1590);
+    assert_eq!(*link.motor_position.borrow(), 42);
+}
+
+#[tokio::test]
+async fn test_watchdog_stops_the_car() {
+    let mut link = TestLink::spawn(MotorLinkConfig {
+        command_timeout: Duration::from_millis(100),
+        heartbeat_interval: Duration::from_millis(20),
+        ..Default::default()
+    });
+    link.send_position(0).await;
+    link.commands
+        .send(MotorControlRequest::SetMotorOutput(100).into())
+        .await
+        .unwrap();
+
+    // acknowledge everything until the watchdog stops the motor
+    let mut heartbeats = 0;
+    loop {
+        let frame = link.next_frame().await;
+        if frame.payload == [MESSAGE_HEARTBEAT] {
+            heartbeats += 1;
+            continue;
+        }
+        link.ack(frame.seq, 0).await;
+        if frame.payload == MotorControlRequest::SetMotorOutput(0).to_payload().unwrap() {
+            break;
+        }
+    }
+    assert!(heartbeats > 0);
+    assert_eq!(*link.watchdog.borrow(), WatchdogState::Tripped);
+    assert_eq!(
+        *link.emergency_stop.borrow(),
+        Some(EmergencyStopReason::Watchdog)
+    );
+
+    // the latch holds until it is reset
+    let reply = link.request(MotorControlRequest::SetMotorOutput(100)).await;
+    assert!(matches!(
+        reply.await,
+        Ok(Err(MotorControlError::EmergencyStopped(
+            EmergencyStopReason::Watchdog
+        )))
+    ));
+    let reply = link.request(MotorControlRequest::ResetEmergencyStop).await;
+    assert_eq!(reply.await.unwrap().unwrap(), 0);
+    assert_eq!(*link.emergency_stop.borrow(), None);
+}
+
+#[tokio::test]
+async fn test_emergency_stop_preempts_the_command_in_flight() {
+    let mut link = TestLink::spawn(MotorLinkConfig::default());
+    link.send_position(0).await;
+    let reply = link.request(MotorControlRequest::SetMotorOutput(100)).await;
+
+    // acknowledge the servo centering but not the motor output, then stop while it is in flight
+    let center = link.next_command().await;
+    link.ack(center.seq, 0).await;
+    let output = link.next_command().await;
+    assert_eq!(
+        output.payload,
+        MotorControlRequest::SetMotorOutput(100)
+            .to_payload()
+            .unwrap()
+    );
+    let (command, stop_reply) = MotorCommand::with_reply(MotorControlRequest::EmergencyStop(
+        EmergencyStopReason::Client,
+    ));
+    link.stops.send(command).unwrap();
+
+    // the stop follows without a retry of the motor output in between
+    let stop = link.next_command().await;
+    assert_eq!(
+        stop.payload,
+        MotorControlRequest::SetMotorOutput(0).to_payload().unwrap()
+    );
+    assert_eq!(stop_reply.await.unwrap().unwrap(), 0);
+    assert_eq!(*link.watchdog.borrow(), WatchdogState::Idle);
+    assert!(matches!(
+        reply.await,
+        Ok(Err(MotorControlError::EmergencyStopped(
+            EmergencyStopReason::Client
+        )))
+    ));
+}
+
+#[tokio::test]
+async fn test_motor_link_coalesces_stale_commands() {
+    let mut link = TestLink::spawn(MotorLinkConfig::default());
+    link.send_position(0).await;
+    let center = link.next_command().await;
+    link.ack(center.seq, 0).await;
+
+    // the first output stays in flight while its acks get lost, and the ones after it pile up
+    let mut replies = vec![link.request(MotorControlRequest::SetMotorOutput(1)).await];
+    let mut outputs = vec![link.next_command().await.payload];
+    for output in 2..=20 {
+        replies.push(
+            link.request(MotorControlRequest::SetMotorOutput(output))
+                .await,
+        );
+    }
+    loop {
+        let frame = link.next_command().await;
+        let last = MotorControlRequest::SetMotorOutput(20)
+            .to_payload()
+            .unwrap();
+        if frame.payload == last {
+            link.ack(frame.seq, 20).await;
+            break;
+        }
+        outputs.push(frame.payload);
+    }
+    // every attempt of the first output and nothing stale in between
+    let first = MotorControlRequest::SetMotorOutput(1).to_payload().unwrap();
+    assert_eq!(outputs, vec![first; MAX_ATTEMPTS as usize]);
+
+    let mut replies = replies.into_iter();
+    assert!(matches!(
+        replies.next().unwrap().await,
+        Ok(Err(MotorControlError::Timeout { .. }))
+    ));
+    assert!(matches!(replies.next_back().unwrap().await, Ok(Ok(20))));
+    for reply in replies {
+        assert!(matches!(
+            reply.await,
+            Ok(Err(MotorControlError::Superseded))
+        ));
+    }
 }
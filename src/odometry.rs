@@ -1,10 +1,157 @@
+use nalgebra::{Isometry2, Matrix2, Matrix3, Matrix3x2, Vector2};
+
 use crate::pose_graph::PositionDiff;
+use crate::steering::SteeringCalibration;
+
+/// Distance the car travels per encoder click of the drive motor, the default for the calibration file
+pub const MM_PER_CLICK: f32 = 0.195364;
+
+/// How uncertain the odometry is, as variances growing with the distance driven. The steering angle enters through
+/// the curvature it drives.
+#[derive(Debug, Clone)]
+pub struct OdometryNoise {
+    /// Variance of the distance driven per millimeter driven, in mm²/mm
+    pub distance: f64,
+    /// Variance of the heading change per millimeter driven, in rad²/mm, slip and uneven floor
+    pub heading: f64,
+    /// Variance of the curvature per unit of curvature, in 1/mm. The harder the car steers the less exact the curvature
+    /// is, and a curvature error turns the heading by an amount growing with the distance.
+    pub steering: f64,
+}
+
+impl Default for OdometryNoise {
+    fn default() -> Self {
+        Self {
+            // 1cm over a meter
+            distance: 0.1,
+            // 2 degrees over a meter
+            heading: 1.2e-6,
+            // 10% of the tightest curvature
+            steering: 1.8e-5,
+        }
+    }
+}
+
+/// Returns the distance and angle traveled by the robot given the servo position it drove with and the encoder clicks
+/// it drove, in the frame of the car at the start. The car drives along an exact arc of the curvature the steering is
+/// calibrated to.
+pub fn odometry_diff(steering: &SteeringCalibration, servo_us: u16, clicks: i32) -> PositionDiff {
+    let dist = clicks as f64 * steering.mm_per_click;
+    let curvature = steering.servo_us_to_curvature(servo_us);
+    let angle = curvature * dist;
+    let translation = if curvature.abs() < 1e-9 {
+        Vector2::new(dist, 0.0)
+    } else {
+        // along a circle of radius 1 / curvature around (0, 1 / curvature)
+        Vector2::new(angle.sin(), 1.0 - angle.cos()) / curvature
+    };
+
+    let noise = &steering.noise;
+    let motion_covariance = Matrix2::from_diagonal(&Vector2::new(
+        noise.distance * dist.abs(),
+        noise.heading * dist.abs() + noise.steering * curvature.abs() * dist * dist,
+    ));
+    // how the translation and rotation change with the distance and the angle, to first order the car drives straight
+    // at half the angle
+    let half = angle / 2.0;
+    let jacobian = Matrix3x2::new(
+        half.cos(),
+        -dist / 2.0 * half.sin(),
+        half.sin(),
+        dist / 2.0 * half.cos(),
+        0.0,
+        1.0,
+    );
+    PositionDiff {
+        translation: translation.cast(),
+        rotation: angle as f32,
+        covariance: (jacobian * motion_covariance * jacobian.transpose()).cast(),
+    }
+}
+
+/// Dead reckoning from the encoder and the servo position, in millimeters and radians from where the car started
+#[derive(Debug, Clone)]
+pub struct OdometryIntegrator {
+    pub steering: SteeringCalibration,
+    pub pose: Isometry2<f64>,
+    /// Covariance of x, y and the heading of the pose, growing as the car drives
+    pub covariance: Matrix3<f64>,
+    /// Encoder position the pose was last updated at, None until the first position arrives
+    last_position: Option<i32>,
+}
+
+impl OdometryIntegrator {
+    pub fn new(steering: SteeringCalibration) -> Self {
+        OdometryIntegrator {
+            steering,
+            pose: Isometry2::identity(),
+            covariance: Matrix3::zeros(),
+            last_position: None,
+        }
+    }
+
+    /// Move the pose along the clicks driven since the last update, assuming the servo was at `servo_us` all along.
+    /// Returns the step, None for the first position.
+    pub fn update(&mut self, position: i32, servo_us: u16) -> Option<PositionDiff> {
+        let last_position = self.last_position.replace(position)?;
+        let diff = odometry_diff(&self.steering, servo_us, position - last_position);
+
+        // the step in the world frame moves with the heading, and is rotated from the car's frame into the world's
+        let step = self.pose.rotation * diff.translation.cast::<f64>();
+        let pose_jacobian = Matrix3::new(1.0, 0.0, -step.y, 0.0, 1.0, step.x, 0.0, 0.0, 1.0);
+        let mut diff_jacobian = Matrix3::identity();
+        diff_jacobian
+            .fixed_view_mut::<2, 2>(0, 0)
+            .copy_from(self.pose.rotation.to_rotation_matrix().matrix());
+        self.covariance = pose_jacobian * self.covariance * pose_jacobian.transpose()
+            + diff_jacobian * diff.covariance.cast::<f64>() * diff_jacobian.transpose();
+
+        self.pose *= Isometry2::new(diff.translation.cast(), diff.rotation as f64);
+        Some(diff)
+    }
+}
+
+#[test]
+fn test_odometry() {
+    use std::f64::consts::PI;
+
+    let steering = SteeringCalibration::default();
+    let servo_us = 1700;
+    let radius = 1.0 / steering.servo_us_to_curvature(servo_us);
+    // clicks for a quarter circle
+    let clicks = (radius * PI / 2.0 / steering.mm_per_click).round() as i32;
+
+    let diff = odometry_diff(&steering, servo_us, clicks);
+    assert!((diff.rotation as f64 - PI / 2.0).abs() < 1e-3);
+    assert!((diff.translation.x as f64 - radius).abs() < 1.0);
+    assert!((diff.translation.y as f64 - radius).abs() < 1.0);
+    let straight = odometry_diff(&steering, steering.curvature_to_servo_us(0.0), 1000);
+    assert!((straight.translation.y as f64).abs() < 1.0);
 
-const MM_PER_CLICK: f32 = 0.195364;
+    // integrating the quarter circle in small steps ends up where the single arc does, a full circle back at the start
+    let mut odometry = OdometryIntegrator::new(steering);
+    for position in (0..=clicks).step_by(7).chain([clicks]) {
+        odometry.update(position, servo_us);
+    }
+    let translation = odometry.pose.translation.vector;
+    assert!((translation.x - diff.translation.x as f64).abs() < 1e-2);
+    assert!((translation.y - diff.translation.y as f64).abs() < 1e-2);
+    for position in (clicks..=4 * clicks).step_by(7).chain([4 * clicks]) {
+        odometry.update(position, servo_us);
+    }
+    assert!(odometry.pose.translation.vector.norm() < 2.0);
 
-/// Returns the distance and angle traveled by the robot given a
-pub fn odometry_diff(servo_us: u16, clicks: i32) {
-    let dist = clicks as f32 * MM_PER_CLICK;
-    let radians_per_5000_clicks = 0.2974285849 * servo_us as f32 - 434.3099174;
-    let angle = radians_per_5000_clicks * clicks as f32 / 5000.0;
+    // driving straight, the uncertainty grows mostly along the way for the distance and sideways for the heading
+    let mut odometry = OdometryIntegrator::new(SteeringCalibration::default());
+    let straight_us = odometry.steering.curvature_to_servo_us(0.0);
+    for position in (0..=5000).step_by(50) {
+        odometry.update(position, straight_us);
+    }
+    let covariance = odometry.covariance;
+    let dist = 5000.0 * odometry.steering.mm_per_click;
+    assert!((covariance[(0, 0)] - 0.1 * dist).abs() < 1.0);
+    assert!((covariance[(2, 2)] - 1.2e-6 * dist).abs() < 1e-6);
+    // the sideways error of a heading error accumulated along the way, a third of what it would be from the start
+    assert!((covariance[(1, 1)] - 1.2e-6 * dist.powi(3) / 3.0).abs() < 10.0);
+    assert!((covariance - covariance.transpose()).norm() < 1e-9);
 }
@@ -1,5 +1,6 @@
 use std::{hint::black_box, time::Instant};
 
+use crate::features::ScanFeatures;
 use crate::lidar::LidarScan;
 
 pub struct PoseGraph {
@@ -10,8 +11,8 @@ impl PoseGraph {
     pub fn new() -> Self {
         PoseGraph { nodes: Vec::new() }
     }
-    /// Add a node from the scan and odometry, and do some processing
-    pub fn add_node(&mut self, scan: LidarScan) {
+    /// Add a node from the scan and the features extracted from its deskewed points, and do some processing
+    pub fn add_node(&mut self, scan: LidarScan, features: ScanFeatures) {
         if let Some(first_node) = self.nodes.first() {
             let start = Instant::now();
             // println!(
@@ -24,17 +25,21 @@ impl PoseGraph {
             //     start.elapsed()
             // );
         }
-        self.nodes.push(Node { scan });
+        self.nodes.push(Node { scan, features });
     }
 }
 
 pub struct Node {
     pub scan: LidarScan,
+    /// Walls and corners seen in the deskewed scan, landmarks for matching
+    pub features: ScanFeatures,
 }
 
 pub struct PositionDiff {
     pub translation: Vector2<f32>,
     pub rotation: f32,
+    /// Covariance of x, y and the rotation, in the frame of the start
+    pub covariance: Matrix3<f32>,
 }
 
 pub struct HomogeneousCoordinate {
@@ -79,7 +84,7 @@ pub fn icp_least_squares(
 }
 
 #[derive(Debug)]
-enum IcpError {
+pub enum IcpError {
     FailedToConverge,
     ErrorTooHigh,
 }
@@ -0,0 +1,155 @@
+use std::f64::consts::PI;
+
+use crate::control_loop::ControlInputs;
+use crate::free_space::{sector_ranges, Direction};
+use crate::motor_control::MotorControlRequest;
+use crate::steering::SteeringCalibration;
+
+#[derive(Debug, Clone)]
+pub struct ReactiveCorridorConfig {
+    /// Start reversing once something ahead is closer than this many millimeters
+    pub front_distance: f64,
+    /// Drive forward again once something behind is closer than this many millimeters
+    pub back_distance: f64,
+    /// Motor output while driving forward, the negative is used while reversing
+    pub motor_output: i16,
+    /// Servo position for driving straight in microseconds
+    pub servo_center: u16,
+    /// How far the servo may turn away from the center in microseconds
+    pub servo_range: u16,
+    /// Servo microseconds per millimeter the left side is more open than the right side
+    pub steering_gain: f64,
+    /// Width in radians of the sectors ahead, behind, left and right of the car the clearances are measured in
+    pub sector_width: f64,
+}
+
+impl Default for ReactiveCorridorConfig {
+    fn default() -> Self {
+        Self {
+            front_distance: 375.0,
+            back_distance: 187.5,
+            motor_output: 220,
+            servo_center: SteeringCalibration::default().center_us,
+            servo_range: 350,
+            steering_gain: 2.0,
+            sector_width: PI / 4.0,
+        }
+    }
+}
+
+/// Drives back and forth along a corridor, reversing before hitting the end and steering towards the more open side
+#[derive(Debug, Clone)]
+pub struct ReactiveCorridorMode {
+    pub config: ReactiveCorridorConfig,
+    /// None until the first scan arrives and the motor is started
+    direction: Option<Direction>,
+}
+
+impl ReactiveCorridorMode {
+    pub fn new(config: ReactiveCorridorConfig) -> Self {
+        ReactiveCorridorMode {
+            config,
+            direction: None,
+        }
+    }
+
+    pub fn step(&mut self, inputs: &ControlInputs) -> Vec<MotorControlRequest> {
+        let (Some(scan), true) = (&inputs.scan, inputs.new_scan) else {
+            return Vec::new();
+        };
+        let points = scan.to_cartesian_points();
+        let half_width = self.config.sector_width / 2.0;
+        // closest point in the sector centered on `angle`, as good as infinitely far if the sector is empty
+        let clearance = |angle: f64| {
+            sector_ranges(&points, angle - half_width, angle + half_width, 1)[0].unwrap_or(f64::MAX)
+        };
+
+        let mut commands = Vec::new();
+        let direction = match self.direction {
+            Some(Direction::Forward) if clearance(0.0) < self.config.front_distance => {
+                Direction::Backward
+            }
+            Some(Direction::Backward) if clearance(PI) < self.config.back_distance => {
+                Direction::Forward
+            }
+            Some(direction) => direction,
+            None => Direction::Forward,
+        };
+        if self.direction != Some(direction) {
+            self.direction = Some(direction);
+            let output = match direction {
+                Direction::Forward => self.config.motor_output,
+                Direction::Backward => -self.config.motor_output,
+            };
+            commands.push(MotorControlRequest::SetMotorOutput(output));
+        }
+
+        let servo_range = self.config.servo_range as f64;
+        let mut steering = ((clearance(PI / 2.0) - clearance(-PI / 2.0))
+            * self.config.steering_gain)
+            .clamp(-servo_range, servo_range);
+        // turning the wheels the same way drives the back of the car the other way
+        if direction == Direction::Backward {
+            steering = -steering;
+        }
+        commands.push(MotorControlRequest::SetServoPosition {
+            microseconds: (self.config.servo_center as f64 + steering).round() as u16,
+        });
+        commands
+    }
+
+    /// Commands leaving the car standing still with straight wheels
+    pub fn stop(&mut self) -> Vec<MotorControlRequest> {
+        self.direction = None;
+        vec![
+            MotorControlRequest::SetMotorOutput(0),
+            MotorControlRequest::SetServoPosition {
+                microseconds: self.config.servo_center,
+            },
+        ]
+    }
+}
+
+#[test]
+fn test_reactive_corridor() {
+    use std::sync::Arc;
+
+    use crate::lidar::LidarScan;
+
+    // a wall ahead at `front` millimeters, the left side 100mm more open than the right
+    let inputs = |front: u32| ControlInputs {
+        scan: Some(Arc::new(LidarScan::from_polar(&[
+            (0.0, front),
+            (90.0, 1000),
+            (270.0, 900),
+        ]))),
+        new_scan: true,
+        motor_position: 0,
+        dt: 0.02,
+    };
+    let mut mode = ReactiveCorridorMode::new(ReactiveCorridorConfig::default());
+
+    let commands = mode.step(&inputs(3000));
+    assert!(matches!(
+        commands[0],
+        MotorControlRequest::SetMotorOutput(220)
+    ));
+    assert!(matches!(
+        commands[1],
+        MotorControlRequest::SetServoPosition { microseconds: 1650 }
+    ));
+    assert_eq!(mode.step(&inputs(1000)).len(), 1);
+    let commands = mode.step(&inputs(300));
+    assert!(matches!(
+        commands[0],
+        MotorControlRequest::SetMotorOutput(-220)
+    ));
+    assert!(matches!(
+        commands[1],
+        MotorControlRequest::SetServoPosition { microseconds: 1250 }
+    ));
+    assert!(matches!(
+        mode.stop()[0],
+        MotorControlRequest::SetMotorOutput(0)
+    ));
+}
@@ -0,0 +1,239 @@
+use std::collections::HashSet;
+
+use kd_tree::KdPoint;
+
+use crate::lidar::{LidarPoint, LidarScan};
+
+/// A filter cleaning up the points of a scan before it is matched, every filter keeps the points in angle order
+#[derive(Debug, Clone)]
+pub enum ScanFilter {
+    /// Drop points closer than `min` or further than `max` millimeters
+    Range { min: u32, max: u32 },
+    /// Replace every distance by the median of the `window` points around it, which removes single point spikes
+    Median { window: usize },
+    /// Drop points with fewer than `min_neighbors` other points within `radius` millimeters
+    RadiusOutlier { radius: f64, min_neighbors: usize },
+    /// Keep only the closest point in every `resolution` degrees
+    AngularDownsample { resolution: f32 },
+    /// Keep only the first point in every square of `size` millimeters
+    VoxelDownsample { size: f64 },
+    /// Drop the further of two neighboring points if the line between them is within `min_angle` radians of the beam,
+    /// these are mixed pixels veiling the edge between a close and a far object
+    Shadow { min_angle: f64 },
+}
+
+impl ScanFilter {
+    pub fn apply(&self, scan: &mut LidarScan) {
+        match *self {
+            ScanFilter::Range { min, max } => scan
+                .points
+                .retain(|point| min <= point.distance_mm && point.distance_mm <= max),
+            ScanFilter::Median { window } => median(&mut scan.points, window),
+            ScanFilter::RadiusOutlier {
+                radius,
+                min_neighbors,
+            } => remove_outliers(&mut scan.points, radius, min_neighbors),
+            ScanFilter::AngularDownsample { resolution } => {
+                let mut kept: Vec<LidarPoint> = Vec::with_capacity(scan.points.len());
+                for point in &scan.points {
+                    let bin = (point.get_angle_degrees() / resolution).floor();
+                    match kept.last_mut() {
+                        Some(last) if (last.get_angle_degrees() / resolution).floor() == bin => {
+                            if point.distance_mm < last.distance_mm {
+                                *last = *point;
+                            }
+                        }
+                        _ => kept.push(*point),
+                    }
+                }
+                scan.points = kept;
+            }
+            ScanFilter::VoxelDownsample { size } => {
+                let mut voxels = HashSet::new();
+                scan.points.retain(|point| {
+                    let position = point.to_cartesian() / size;
+                    voxels.insert((position.x.floor() as i64, position.y.floor() as i64))
+                });
+            }
+            ScanFilter::Shadow { min_angle } => remove_shadows(&mut scan.points, min_angle),
+        }
+    }
+}
+
+/// Filters applied one after the other
+#[derive(Debug, Clone)]
+pub struct ScanFilterPipeline {
+    pub filters: Vec<ScanFilter>,
+}
+
+impl ScanFilterPipeline {
+    pub fn new(filters: Vec<ScanFilter>) -> Self {
+        ScanFilterPipeline { filters }
+    }
+
+    pub fn apply(&self, scan: &mut LidarScan) {
+        for filter in &self.filters {
+            filter.apply(scan);
+        }
+    }
+}
+
+impl Default for ScanFilterPipeline {
+    /// Tuned for the A1M8, which measures from 150mm to 12m
+    fn default() -> Self {
+        ScanFilterPipeline::new(vec![
+            ScanFilter::Range {
+                min: 150,
+                max: 12000,
+            },
+            ScanFilter::Shadow {
+                min_angle: 10f64.to_radians(),
+            },
+            ScanFilter::Median { window: 3 },
+            ScanFilter::RadiusOutlier {
+                radius: 100.0,
+                min_neighbors: 2,
+            },
+            // express scans measure about three points per degree, the closest one in every half degree is enough
+            ScanFilter::AngularDownsample { resolution: 0.5 },
+            ScanFilter::VoxelDownsample { size: 20.0 },
+        ])
+    }
+}
+
+fn median(points: &mut [LidarPoint], window: usize) {
+    let half = window / 2;
+    let distances: Vec<u32> = points.iter().map(|point| point.distance_mm).collect();
+    let mut neighborhood = Vec::with_capacity(window);
+    for (i, point) in points.iter_mut().enumerate() {
+        neighborhood.clear();
+        neighborhood.extend_from_slice(
+            &distances[i.saturating_sub(half)..(i + half + 1).min(distances.len())],
+        );
+        neighborhood.sort_unstable();
+        point.distance_mm = neighborhood[neighborhood.len() / 2];
+    }
+}
+
+struct FilterPoint {
+    position: [f64; 2],
+}
+
+impl KdPoint for FilterPoint {
+    type Scalar = f64;
+    type Dim = typenum::U2;
+    fn at(&self, i: usize) -> Self::Scalar {
+        self.position[i]
+    }
+}
+
+fn remove_outliers(points: &mut Vec<LidarPoint>, radius: f64, min_neighbors: usize) {
+    let positions: Vec<FilterPoint> = points
+        .iter()
+        .map(|point| {
+            let position = point.to_cartesian();
+            FilterPoint {
+                position: [position.x, position.y],
+            }
+        })
+        .collect();
+    let kdtree = kd_tree::KdTree::build_by_ordered_float(
+        positions
+            .iter()
+            .map(|point| FilterPoint {
+                position: point.position,
+            })
+            .collect(),
+    );
+    let mut positions = positions.iter();
+    points.retain(|_| {
+        let position = positions.next().unwrap();
+        // the point itself is within the radius too
+        kdtree.within_radius(position, radius).len() > min_neighbors
+    });
+}
+
+fn remove_shadows(points: &mut Vec<LidarPoint>, min_angle: f64) {
+    let mut shadowed = vec![false; points.len()];
+    for i in 1..points.len() {
+        let (first, second) = (&points[i - 1], &points[i]);
+        let beam_angle = second.get_angle_rad_f64() - first.get_angle_rad_f64();
+        let (r1, r2) = (first.distance_mm as f64, second.distance_mm as f64);
+        // angle at the first point between its beam and the line to the second point
+        let angle = (r2 * beam_angle.sin())
+            .atan2(r1 - r2 * beam_angle.cos())
+            .abs();
+        if angle < min_angle || angle > std::f64::consts::PI - min_angle {
+            shadowed[if r1 > r2 { i - 1 } else { i }] = true;
+        }
+    }
+    let mut shadowed = shadowed.into_iter();
+    points.retain(|_| !shadowed.next().unwrap());
+}
+
+#[test]
+fn test_range_median_and_outliers() {
+    let distances = |scan: &LidarScan| {
+        scan.points
+            .iter()
+            .map(|point| point.distance_mm)
+            .collect::<Vec<_>>()
+    };
+    let mut scan = LidarScan::from_polar(&[
+        (0.0, 100),
+        (1.0, 1000),
+        (2.0, 1005),
+        (3.0, 5000),
+        (4.0, 1010),
+        (5.0, 1012),
+    ]);
+    ScanFilter::Range {
+        min: 150,
+        max: 12000,
+    }
+    .apply(&mut scan);
+    assert_eq!(distances(&scan), vec![1000, 1005, 5000, 1010, 1012]);
+    ScanFilter::Median { window: 3 }.apply(&mut scan);
+    assert_eq!(distances(&scan), vec![1005, 1005, 1010, 1012, 1012]);
+
+    // a lone speckle far away from a wall
+    let mut scan = LidarScan::from_polar(&[(0.0, 1000), (0.5, 1000), (1.0, 1000), (90.0, 3000)]);
+    ScanFilter::RadiusOutlier {
+        radius: 50.0,
+        min_neighbors: 2,
+    }
+    .apply(&mut scan);
+    assert_eq!(scan.points.len(), 3);
+}
+
+#[test]
+fn test_downsample_and_shadows() {
+    let mut scan = LidarScan::from_polar(&[(0.0, 1000), (0.5, 990), (1.2, 1000), (1.5, 1000)]);
+    ScanFilter::AngularDownsample { resolution: 1.0 }.apply(&mut scan);
+    assert_eq!(scan.points.len(), 2);
+    assert_eq!(scan.points[0].distance_mm, 990);
+
+    let mut scan = LidarScan::from_polar(&[(0.0, 1005), (0.1, 1005), (0.2, 1005)]);
+    ScanFilter::VoxelDownsample { size: 10.0 }.apply(&mut scan);
+    assert_eq!(scan.points.len(), 1);
+
+    // a mixed pixel between an edge at 1000mm and a wall at 3000mm lies almost along the beam
+    let mut scan = LidarScan::from_polar(&[
+        (0.0, 1000),
+        (1.0, 1000),
+        (2.0, 2000),
+        (3.0, 3000),
+        (4.0, 3000),
+    ]);
+    ScanFilter::Shadow {
+        min_angle: 10f64.to_radians(),
+    }
+    .apply(&mut scan);
+    assert_eq!(
+        scan.points
+            .iter()
+            .map(|point| point.distance_mm)
+            .collect::<Vec<_>>(),
+        vec![1000, 1000, 3000]
+    );
+}
@@ -0,0 +1,150 @@
+use crate::control_loop::ControlInputs;
+use crate::motor_control::MotorControlRequest;
+use crate::odometry::MM_PER_CLICK;
+
+#[derive(Debug, Clone)]
+pub struct SpeedControllerConfig {
+    /// Motor output per mm/s of speed error
+    pub kp: f64,
+    /// Motor output per millimeter of accumulated speed error
+    pub ki: f64,
+    /// Motor output per mm/s² of change in speed error
+    pub kd: f64,
+    /// Motor output per mm/s of setpoint, roughly what the motor needs to hold the speed on its own
+    pub feedforward: f64,
+    /// Motor output needed to overcome friction, added in the direction of the setpoint
+    pub static_output: f64,
+    /// How quickly the setpoint may approach the target speed in mm/s²
+    pub max_acceleration: f64,
+    /// Largest motor output sent in either direction, at most 255
+    pub max_output: f64,
+    /// Weight of the newest measurement in the low pass filtered speed estimate, 1 is no filtering
+    pub speed_smoothing: f64,
+    /// Distance the car travels per encoder click
+    pub mm_per_click: f64,
+}
+
+impl Default for SpeedControllerConfig {
+    fn default() -> Self {
+        Self {
+            kp: 0.1,
+            ki: 0.2,
+            kd: 0.0,
+            feedforward: 0.12,
+            static_output: 30.0,
+            max_acceleration: 1000.0,
+            max_output: 255.0,
+            speed_smoothing: 0.3,
+            mm_per_click: MM_PER_CLICK as f64,
+        }
+    }
+}
+
+/// PID with feedforward on the wheel speed estimated from the encoder, turning a target speed into motor output
+#[derive(Debug, Clone)]
+pub struct SpeedController {
+    pub config: SpeedControllerConfig,
+    /// Speed to reach in mm/s, negative is backwards
+    pub target: f64,
+    /// The target with the acceleration limit applied, what the PID actually follows
+    setpoint: f64,
+    /// Filtered wheel speed in mm/s
+    speed: f64,
+    integral: f64,
+    last_error: Option<f64>,
+    last_position: Option<i32>,
+}
+
+impl SpeedController {
+    pub fn new(config: SpeedControllerConfig, target: f64) -> Self {
+        SpeedController {
+            config,
+            target,
+            setpoint: 0.0,
+            speed: 0.0,
+            integral: 0.0,
+            last_error: None,
+            last_position: None,
+        }
+    }
+
+    /// Update the speed estimate with the encoder position `dt` seconds after the last one and compute the motor output
+    pub fn update(&mut self, position: i32, dt: f64) -> i16 {
+        let config = &self.config;
+        if let Some(last_position) = self.last_position {
+            let measured = (position - last_position) as f64 * config.mm_per_click / dt;
+            self.speed += config.speed_smoothing * (measured - self.speed);
+        }
+        self.last_position = Some(position);
+
+        let max_change = config.max_acceleration * dt;
+        self.setpoint += (self.target - self.setpoint).clamp(-max_change, max_change);
+        if self.setpoint == 0.0 {
+            // standing still, nothing to hold against
+            self.integral = 0.0;
+            self.last_error = None;
+            return 0;
+        }
+
+        let error = self.setpoint - self.speed;
+        let derivative = self
+            .last_error
+            .map_or(0.0, |last_error| (error - last_error) / dt);
+        self.last_error = Some(error);
+        let feedforward =
+            config.feedforward * self.setpoint + config.static_output * self.setpoint.signum();
+        let unclamped = feedforward
+            + config.kp * error
+            + config.ki * (self.integral + error * dt)
+            + config.kd * derivative;
+        let output = unclamped.clamp(-config.max_output, config.max_output);
+        // anti-windup: stop integrating while saturated, unless the error pulls the output back into range
+        if output == unclamped || error.signum() != unclamped.signum() {
+            self.integral += error * dt;
+        }
+        output.round() as i16
+    }
+
+    pub fn step(&mut self, inputs: &ControlInputs) -> Vec<MotorControlRequest> {
+        // sent every tick, even if unchanged, to keep the motor watchdog fed
+        vec![MotorControlRequest::SetMotorOutput(
+            self.update(inputs.motor_position, inputs.dt),
+        )]
+    }
+
+    /// Commands stopping the motor, the controller starts from standstill again afterwards
+    pub fn stop(&mut self) -> Vec<MotorControlRequest> {
+        self.setpoint = 0.0;
+        self.speed = 0.0;
+        self.integral = 0.0;
+        self.last_error = None;
+        self.last_position = None;
+        vec![MotorControlRequest::SetMotorOutput(0)]
+    }
+}
+
+#[test]
+fn test_speed_controller() {
+    let mut controller = SpeedController::new(SpeedControllerConfig::default(), 500.0);
+    // a motor which needs more output than the feedforward assumes and takes a while to respond
+    let dt = 0.02;
+    let mut speed = 0.0;
+    let mut position: f64 = 0.0;
+    let mut last_setpoint = 0.0;
+    for _ in 0..500 {
+        let output = controller.update(position.round() as i32, dt);
+        speed += (output as f64 * 5.0 - 150.0 - speed) * dt / 0.2;
+        position += speed * dt / MM_PER_CLICK as f64;
+        // the setpoint ramps up no faster than the acceleration limit
+        assert!(controller.setpoint - last_setpoint <= 1000.0 * dt + 1e-9);
+        last_setpoint = controller.setpoint;
+    }
+    assert!((speed - 500.0).abs() < 10.0, "{}", speed);
+    assert!((controller.speed - 500.0).abs() < 20.0);
+
+    controller.target = 0.0;
+    for _ in 0..100 {
+        controller.update(position.round() as i32, dt);
+    }
+    assert_eq!(controller.update(position.round() as i32, dt), 0);
+}
@@ -0,0 +1,293 @@
+//! Steering calibration, the mapping between servo microseconds and the curvature the car drives along (1 / turning
+//! radius in millimeters, positive to the left), along with the distance the car travels per encoder click and the
+//! noise of the odometry
+//!
+//! The file format is plain text, one entry per line with `#` starting a comment:
+//!
+//! ```text
+//! center 1450
+//! min 1100
+//! max 1800
+//! mm_per_click 0.195364
+//! distance_noise 0.1
+//! heading_noise 0.0000012
+//! steering_noise 0.000018
+//! # servo microseconds and the curvature they drive, sorted by microseconds
+//! 1100 -0.00191
+//! 1800 0.00181
+//! ```
+
+use std::fmt;
+use std::path::Path;
+
+use crate::odometry::{OdometryNoise, MM_PER_CLICK};
+
+#[derive(Debug, Clone)]
+pub struct SteeringCalibration {
+    /// Servo position for driving straight in microseconds
+    pub center_us: u16,
+    /// Servo positions past these would push the steering against its end stops
+    pub min_us: u16,
+    pub max_us: u16,
+    /// Servo microseconds and the curvature they drive, interpolated linearly in between. Sorted by microseconds, the
+    /// curvature has to be strictly monotonic for the mapping to be invertible.
+    pub table: Vec<(u16, f64)>,
+    /// Distance the car travels per encoder click of the drive motor
+    pub mm_per_click: f64,
+    /// How much the odometry drifts while driving on this calibration
+    pub noise: OdometryNoise,
+}
+
+impl Default for SteeringCalibration {
+    fn default() -> Self {
+        // the linear fit the car was driven with before there was a calibration file, which gives degrees per 5000
+        // clicks judging by its magnitude
+        let curvature = |us: u16| {
+            (0.2974285849 * us as f64 - 434.3099174).to_radians() / (5000.0 * MM_PER_CLICK as f64)
+        };
+        Self {
+            center_us: 1450,
+            min_us: 1100,
+            max_us: 1800,
+            table: vec![(1100, curvature(1100)), (1800, curvature(1800))],
+            mm_per_click: MM_PER_CLICK as f64,
+            noise: OdometryNoise::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SteeringCalibrationError {
+    Io(std::io::Error),
+    /// A line that isn't a known key or a table entry, numbered from 1
+    Parse {
+        line: usize,
+        text: String,
+    },
+    /// The values parsed but don't make a usable calibration
+    Invalid(&'static str),
+}
+
+impl fmt::Display for SteeringCalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SteeringCalibrationError::Io(err) => write!(f, "failed to read calibration: {}", err),
+            SteeringCalibrationError::Parse { line, text } => {
+                write!(f, "can't parse line {}: {:?}", line, text)
+            }
+            SteeringCalibrationError::Invalid(reason) => {
+                write!(f, "invalid calibration: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SteeringCalibrationError {}
+
+impl From<std::io::Error> for SteeringCalibrationError {
+    fn from(err: std::io::Error) -> Self {
+        SteeringCalibrationError::Io(err)
+    }
+}
+
+impl SteeringCalibration {
+    pub fn load<Q: AsRef<Path>>(path: Q) -> Result<Self, SteeringCalibrationError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, SteeringCalibrationError> {
+        let (mut center_us, mut min_us, mut max_us) = (None, None, None);
+        // older files only have the steering
+        let mut mm_per_click = MM_PER_CLICK as f64;
+        let mut noise = OdometryNoise::default();
+        let mut table = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let content = line.split('#').next().unwrap().trim();
+            if content.is_empty() {
+                continue;
+            }
+            let parse_error = || SteeringCalibrationError::Parse {
+                line: index + 1,
+                text: line.to_string(),
+            };
+            let mut words = content.split_whitespace();
+            let (Some(key), Some(value), None) = (words.next(), words.next(), words.next()) else {
+                return Err(parse_error());
+            };
+            match key {
+                "center" => center_us = Some(value.parse().map_err(|_| parse_error())?),
+                "min" => min_us = Some(value.parse().map_err(|_| parse_error())?),
+                "max" => max_us = Some(value.parse().map_err(|_| parse_error())?),
+                "mm_per_click" => mm_per_click = value.parse().map_err(|_| parse_error())?,
+                "distance_noise" => noise.distance = value.parse().map_err(|_| parse_error())?,
+                "heading_noise" => noise.heading = value.parse().map_err(|_| parse_error())?,
+                "steering_noise" => noise.steering = value.parse().map_err(|_| parse_error())?,
+                _ => {
+                    let us = key.parse().map_err(|_| parse_error())?;
+                    let curvature = value.parse().map_err(|_| parse_error())?;
+                    table.push((us, curvature));
+                }
+            }
+        }
+        let (Some(center_us), Some(min_us), Some(max_us)) = (center_us, min_us, max_us) else {
+            return Err(SteeringCalibrationError::Invalid(
+                "center, min and max are required",
+            ));
+        };
+        let calibration = SteeringCalibration {
+            center_us,
+            min_us,
+            max_us,
+            table,
+            mm_per_click,
+            noise,
+        };
+        calibration.validate()?;
+        Ok(calibration)
+    }
+
+    pub fn save<Q: AsRef<Path>>(&self, path: Q) -> Result<(), SteeringCalibrationError> {
+        std::fs::write(path, self.to_text())?;
+        Ok(())
+    }
+
+    /// The calibration in the file format read by [`Self::parse`]
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "center {}\nmin {}\nmax {}\nmm_per_click {}\ndistance_noise {}\nheading_noise {}\nsteering_noise {}\n\
+             # servo microseconds and the curvature they drive\n",
+            self.center_us,
+            self.min_us,
+            self.max_us,
+            self.mm_per_click,
+            self.noise.distance,
+            self.noise.heading,
+            self.noise.steering
+        );
+        for (us, curvature) in &self.table {
+            text += &format!("{} {}\n", us, curvature);
+        }
+        text
+    }
+
+    pub fn validate(&self) -> Result<(), SteeringCalibrationError> {
+        if !(self.min_us <= self.center_us && self.center_us <= self.max_us) {
+            return Err(SteeringCalibrationError::Invalid(
+                "center has to be between min and max",
+            ));
+        }
+        if self.mm_per_click.is_nan() || self.mm_per_click <= 0.0 {
+            return Err(SteeringCalibrationError::Invalid(
+                "mm_per_click has to be positive",
+            ));
+        }
+        let noise = [self.noise.distance, self.noise.heading, self.noise.steering];
+        if noise
+            .iter()
+            .any(|variance| variance.is_nan() || *variance < 0.0)
+        {
+            return Err(SteeringCalibrationError::Invalid(
+                "the noise variances can't be negative",
+            ));
+        }
+        if self.table.len() < 2 {
+            return Err(SteeringCalibrationError::Invalid(
+                "the table needs at least two entries",
+            ));
+        }
+        if self.table.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(SteeringCalibrationError::Invalid(
+                "the table has to be sorted by microseconds",
+            ));
+        }
+        let increasing = self.table[1].1 > self.table[0].1;
+        if self
+            .table
+            .windows(2)
+            .any(|pair| (pair[1].1 > pair[0].1) != increasing || pair[1].1 == pair[0].1)
+        {
+            return Err(SteeringCalibrationError::Invalid(
+                "the curvature has to be strictly monotonic",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Curvature the car drives along with the servo at `us`, clamped to the servo limits and extrapolated past the ends
+    /// of the table
+    pub fn servo_us_to_curvature(&self, us: u16) -> f64 {
+        let us = us.clamp(self.min_us, self.max_us) as f64;
+        let table: Vec<(f64, f64)> = self
+            .table
+            .iter()
+            .map(|&(us, curvature)| (us as f64, curvature))
+            .collect();
+        interpolate(&table, us)
+    }
+
+    /// Servo position driving along `curvature`, the inverse of [`Self::servo_us_to_curvature`]. Curvatures tighter
+    /// than the steering allows end up at the servo limits, and curvatures which aren't finite steer straight.
+    pub fn curvature_to_servo_us(&self, curvature: f64) -> u16 {
+        if !curvature.is_finite() {
+            return self.center_us;
+        }
+        let mut table: Vec<(f64, f64)> = self
+            .table
+            .iter()
+            .map(|&(us, curvature)| (curvature, us as f64))
+            .collect();
+        if table[0].0 > table[1].0 {
+            table.reverse();
+        }
+        let us = interpolate(&table, curvature);
+        (us.round().clamp(self.min_us as f64, self.max_us as f64)) as u16
+    }
+}
+
+/// Linear interpolation in a table sorted by its first column, extrapolating with the outermost entries
+fn interpolate(table: &[(f64, f64)], x: f64) -> f64 {
+    let segment = table
+        .windows(2)
+        .position(|pair| x <= pair[1].0)
+        .unwrap_or(table.len() - 2);
+    let (x0, y0) = table[segment];
+    let (x1, y1) = table[segment + 1];
+    y0 + (x - x0) / (x1 - x0) * (y1 - y0)
+}
+
+#[test]
+fn test_steering_calibration() {
+    let calibration = SteeringCalibration::parse(
+        "center 1450
+        min 1100
+        max 1800 # end stops
+        # a right turn is tighter than a left turn
+        1100 -0.002
+        1450 0
+        1800 0.001",
+    )
+    .unwrap();
+    assert_eq!(calibration.servo_us_to_curvature(1450), 0.0);
+    assert!((calibration.servo_us_to_curvature(1275) + 0.001).abs() < 1e-12);
+    assert_eq!(calibration.servo_us_to_curvature(2000), 0.001);
+    assert_eq!(calibration.curvature_to_servo_us(0.0), 1450);
+    assert_eq!(calibration.curvature_to_servo_us(0.0005), 1625);
+    assert_eq!(calibration.curvature_to_servo_us(-1.0), 1100);
+    assert_eq!(calibration.curvature_to_servo_us(f64::NAN), 1450);
+    assert_eq!(calibration.curvature_to_servo_us(f64::INFINITY), 1450);
+    for us in [1100, 1300, 1450, 1600, 1800] {
+        let curvature = calibration.servo_us_to_curvature(us);
+        assert_eq!(calibration.curvature_to_servo_us(curvature), us);
+    }
+
+    assert!(matches!(
+        SteeringCalibration::parse("center 1450\nmin 1100\nmax 1800\n1100 0.001\n1450 0.001"),
+        Err(SteeringCalibrationError::Invalid(_))
+    ));
+    assert!(matches!(
+        SteeringCalibration::parse("center 1450\nmin eleven"),
+        Err(SteeringCalibrationError::Parse { line: 2, .. })
+    ));
+    // the default is a valid calibration
+    SteeringCalibration::default().validate().unwrap();
+}
@@ -4,8 +4,10 @@ use tokio::net::TcpStream;
 use tokio_serial::SerialStream;
 
 use crate::{
-    lidar::{LidarEngine, LidarScan},
-    motor_control::MotorControlRequest,
+    drive_distance::DriveDistanceError,
+    features::ScanFeatures,
+    lidar::{LidarEngine, LidarScan, LidarStatus},
+    motor_control::{EmergencyStopReason, MotorControlError, MotorControlReply, WatchdogState},
 };
 
 pub struct Client {
@@ -44,6 +46,17 @@ impl Client {
                         1 => 1,
                         2 => 3,
                         3 => 3,
+                        4 => 1,
+                        5 => 1,
+                        6 => 1,
+                        7 => 1,
+                        8 => 1,
+                        9 => 1,
+                        10 => 1,
+                        11 => 5,
+                        12 => 5,
+                        13 => 1,
+                        14 => 1,
                         _ => {
                             return Err(std::io::Error::new(
                                 std::io::ErrorKind::InvalidData,
@@ -65,6 +78,24 @@ impl Client {
                                 let output = i16::from_le_bytes([buf[1], buf[2]]);
                                 ClientToCar::SetMotorOutput(output)
                             }
+                            4 => ClientToCar::GetLidarStatus,
+                            5 => ClientToCar::StartReactiveCorridor,
+                            6 => ClientToCar::StopDriveMode,
+                            7 => ClientToCar::GetWatchdogStatus,
+                            8 => ClientToCar::EmergencyStop,
+                            9 => ClientToCar::ResetEmergencyStop,
+                            10 => ClientToCar::GetEmergencyStopStatus,
+                            11 => {
+                                let millimeters =
+                                    f32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]);
+                                ClientToCar::DriveDistance { millimeters }
+                            }
+                            12 => {
+                                let mm_per_s = f32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]);
+                                ClientToCar::SetTargetSpeed { mm_per_s }
+                            }
+                            13 => ClientToCar::StartCalibration,
+                            14 => ClientToCar::GetScanFeatures,
                             _ => {
                                 return Err(std::io::Error::new(
                                     std::io::ErrorKind::InvalidData,
@@ -90,14 +121,88 @@ impl Client {
 pub enum ClientToCar {
     GetCurrentPose,
     GetMostRecentLidarScan,
-    SetServoPosition { microseconds: u16 },
+    SetServoPosition {
+        microseconds: u16,
+    },
     SetMotorOutput(i16),
+    GetLidarStatus,
+    /// Drive back and forth along the corridor on its own
+    StartReactiveCorridor,
+    /// Stop the car and hand control back to the clients
+    StopDriveMode,
+    GetWatchdogStatus,
+    /// Stop the car immediately and reject motor output until the stop is reset
+    EmergencyStop,
+    ResetEmergencyStop,
+    GetEmergencyStopStatus,
+    /// Drive this far on the encoder, negative is backwards. Answered with a DriveDistanceResult once the car stops.
+    DriveDistance {
+        millimeters: f32,
+    },
+    /// Drive at this speed with the speed controller, negative is backwards. Left with StopDriveMode.
+    SetTargetSpeed {
+        mm_per_s: f32,
+    },
+    /// Drive the calibration runs and save the fitted steering calibration, needs room around the car
+    StartCalibration,
+    /// The walls and corners seen in the most recent scan of the pose graph
+    GetScanFeatures,
+}
+
+impl ClientToCar {
+    /// The packet id the client sent this with
+    pub fn id(&self) -> u8 {
+        match self {
+            ClientToCar::GetCurrentPose => 0,
+            ClientToCar::GetMostRecentLidarScan => 1,
+            ClientToCar::SetServoPosition { .. } => 2,
+            ClientToCar::SetMotorOutput(_) => 3,
+            ClientToCar::GetLidarStatus => 4,
+            ClientToCar::StartReactiveCorridor => 5,
+            ClientToCar::StopDriveMode => 6,
+            ClientToCar::GetWatchdogStatus => 7,
+            ClientToCar::EmergencyStop => 8,
+            ClientToCar::ResetEmergencyStop => 9,
+            ClientToCar::GetEmergencyStopStatus => 10,
+            ClientToCar::DriveDistance { .. } => 11,
+            ClientToCar::SetTargetSpeed { .. } => 12,
+            ClientToCar::StartCalibration => 13,
+            ClientToCar::GetScanFeatures => 14,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum CarToClient<'a> {
-    CurrentPose { x: f32, y: f32, theta: f32 },
-    LidarScan { scan: &'a LidarScan },
+    CurrentPose {
+        x: f32,
+        y: f32,
+        theta: f32,
+    },
+    LidarScan {
+        scan: &'a LidarScan,
+    },
+    LidarStatus {
+        status: &'a LidarStatus,
+    },
+    WatchdogStatus {
+        state: WatchdogState,
+    },
+    /// None while the car isn't stopped
+    EmergencyStopStatus {
+        reason: Option<EmergencyStopReason>,
+    },
+    DriveDistanceResult {
+        result: Result<f32, DriveDistanceError>,
+    },
+    /// Whether the Arduino applied a motor request of the client, `request` is the id of the ClientToCar packet
+    MotorReply {
+        request: u8,
+        reply: MotorControlReply,
+    },
+    ScanFeatures {
+        features: &'a ScanFeatures,
+    },
 }
 
 impl CarToClient<'_> {
@@ -117,10 +222,108 @@ impl CarToClient<'_> {
                     .await?;
                 for point in &scan.points {
                     stream.write(&point.angle_q6.to_le_bytes()).await?;
-                    stream.write(&point.distance_q0.to_le_bytes()).await?;
+                    // the protocol sends distances in quarter millimeters
+                    stream
+                        .write(&(point.distance_mm << 2).to_le_bytes())
+                        .await?;
                     stream.write(&point.index.to_le_bytes()).await?;
                 }
             }
+            CarToClient::LidarStatus { status } => {
+                // status code followed by the length prefixed failure message, which is empty unless it failed, and the
+                // f32 scan frequency in Hz, which is 0 until it has been measured
+                let (code, message, frequency) = match status {
+                    LidarStatus::Initializing => (0u8, "", 0.0),
+                    LidarStatus::Running { scan_frequency_hz } => {
+                        (1, "", scan_frequency_hz.unwrap_or(0.0))
+                    }
+                    LidarStatus::Failed(message) => (2, message.as_str(), 0.0),
+                };
+                stream.write_all(&[2, code]).await?;
+                stream
+                    .write_all(&(message.len() as u32).to_le_bytes())
+                    .await?;
+                stream.write_all(message.as_bytes()).await?;
+                stream.write_all(&frequency.to_le_bytes()).await?;
+            }
+            CarToClient::WatchdogStatus { state } => {
+                let code = match state {
+                    WatchdogState::Idle => 0u8,
+                    WatchdogState::Armed => 1,
+                    WatchdogState::Tripped => 2,
+                };
+                stream.write_all(&[3, code]).await?;
+            }
+            CarToClient::EmergencyStopStatus { reason } => {
+                // reason code followed by the f32 distance to the obstacle, which is 0 unless it was a proximity stop
+                let (code, distance) = match reason {
+                    None => (0u8, 0.0),
+                    Some(EmergencyStopReason::Client) => (1, 0.0),
+                    Some(EmergencyStopReason::Proximity { distance }) => (2, *distance as f32),
+                    Some(EmergencyStopReason::Watchdog) => (3, 0.0),
+                };
+                stream.write_all(&[4, code]).await?;
+                stream.write_all(&distance.to_le_bytes()).await?;
+            }
+            CarToClient::DriveDistanceResult { result } => {
+                // result code followed by the f32 millimeters actually driven, 0 if the move never started
+                let (code, traveled) = match result {
+                    Ok(traveled) => (0u8, *traveled),
+                    Err(DriveDistanceError::Stalled { traveled }) => (1, *traveled),
+                    Err(DriveDistanceError::Motor(_)) => (2, 0.0),
+                    Err(DriveDistanceError::InvalidDistance(_)) => (3, 0.0),
+                };
+                stream.write_all(&[5, code]).await?;
+                stream.write_all(&traveled.to_le_bytes()).await?;
+            }
+            CarToClient::MotorReply { request, reply } => {
+                // result code followed by the i32 value the Arduino applied, 0 if it didn't
+                let (code, value) = match reply {
+                    Ok(value) => (0u8, *value),
+                    Err(MotorControlError::LinkDown) => (1, 0),
+                    Err(MotorControlError::Timeout { .. }) => (2, 0),
+                    Err(MotorControlError::EmergencyStopped(_)) => (3, 0),
+                    Err(MotorControlError::Io(_)) => (4, 0),
+                    Err(MotorControlError::Superseded) => (5, 0),
+                };
+                stream.write_all(&[6, *request, code]).await?;
+                stream.write_all(&value.to_le_bytes()).await?;
+            }
+            CarToClient::ScanFeatures { features } => {
+                // the u32 count of segments, each as f32 start x, y, end x, y, normal x, y, distance, the variance of the
+                // normal angle, its covariance with the distance and the variance of the distance followed by the u32
+                // point count, then the u32 count of corners, each as f32 x, y, angle and the u32 indices of its two
+                // segments
+                let mut bytes = vec![7];
+                bytes.extend((features.segments.len() as u32).to_le_bytes());
+                for segment in &features.segments {
+                    let covariance = &segment.covariance;
+                    for value in [
+                        segment.start.x,
+                        segment.start.y,
+                        segment.end.x,
+                        segment.end.y,
+                        segment.normal.x,
+                        segment.normal.y,
+                        segment.distance,
+                        covariance[(0, 0)],
+                        covariance[(0, 1)],
+                        covariance[(1, 1)],
+                    ] {
+                        bytes.extend((value as f32).to_le_bytes());
+                    }
+                    bytes.extend((segment.point_count as u32).to_le_bytes());
+                }
+                bytes.extend((features.corners.len() as u32).to_le_bytes());
+                for corner in &features.corners {
+                    for value in [corner.position.x, corner.position.y, corner.angle] {
+                        bytes.extend((value as f32).to_le_bytes());
+                    }
+                    bytes.extend((corner.segments.0 as u32).to_le_bytes());
+                    bytes.extend((corner.segments.1 as u32).to_le_bytes());
+                }
+                stream.write_all(&bytes).await?;
+            }
         }
         Ok(())
     }